@@ -0,0 +1,7 @@
+CREATE SCHEMA IF NOT EXISTS rust_test;
+
+CREATE TABLE IF NOT EXISTS rust_test.chuck (
+    id BIGSERIAL PRIMARY KEY,
+    value TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS rust_test.grocery_item (
+    name TEXT PRIMARY KEY,
+    quantity INTEGER NOT NULL
+);
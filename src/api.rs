@@ -1,8 +1,15 @@
 use async_trait::async_trait;
+use lazy_static::lazy_static;
 use reqwest::Error;
-use reqwest::header::HeaderMap;
 use serde::{Deserialize, Serialize};
-use warp::http::HeaderValue;
+
+use proper_rust::flow_logger;
+use proper_rust::flow_logger::{FlowContext, FlowLogger};
+use proper_rust::http_client::HttpClient;
+
+lazy_static! {
+    static ref LOG: FlowLogger = flow_logger!("app::chuck");
+}
 
 #[derive(Deserialize, Serialize)]
 pub struct Chuck {
@@ -15,30 +22,26 @@ pub struct ChuckConfig {
 
 #[async_trait]
 pub trait ChuckApiService {
-    async fn make_call(&self) -> Result<Chuck, Error>;
+    async fn make_call(&self, fc: &FlowContext) -> Result<Chuck, Error>;
 }
 
 pub struct ChuckApiServiceImpl {
     config: ChuckConfig,
+    client: HttpClient,
 }
 
 impl ChuckApiServiceImpl {
-    pub fn new(config: ChuckConfig) -> Self {
-        ChuckApiServiceImpl { config }
+    pub fn new(config: ChuckConfig, client: HttpClient) -> Self {
+        ChuckApiServiceImpl { config, client }
     }
 }
 
 #[async_trait]
 impl ChuckApiService for ChuckApiServiceImpl {
-    async fn make_call(&self) -> Result<Chuck, Error> {
-        let client = reqwest::ClientBuilder::new();
-        let mut map = HeaderMap::new();
-        map.insert("test", HeaderValue::from_static("test"));
-        let client2 = client.default_headers(map);
-        let client3 = client2.build().unwrap();
-        let res = client3.get(self.config.url.as_str()).send().await?;
-        println!("Status: {}", res.status());
-        let body: Chuck = res.json().await?;
+    async fn make_call(&self, fc: &FlowContext) -> Result<Chuck, Error> {
+        let res = self.client.send(fc, self.client.get(self.config.url.as_str())).await?;
+        LOG.debug(fc, format!("chuck api responded {}", res.status()).as_str());
+        let body: Chuck = res.error_for_status()?.json().await?;
         Ok(body)
     }
 }
@@ -47,6 +50,8 @@ impl ChuckApiService for ChuckApiServiceImpl {
 mod tests {
     use mockito::mock;
 
+    use proper_rust::settings::HttpClientSettings;
+
     use super::*;
 
     macro_rules! aw {
@@ -58,7 +63,7 @@ mod tests {
     #[test]
     fn test_make_call() {
         let _m = mock("GET", "/jokes/random")
-            .match_header("test", "test")
+            .match_header("flow-id", "my-flow")
             .with_status(200)
             .with_header("content-type", "text/plain")
             .with_header("x-api-key", "1234")
@@ -67,12 +72,30 @@ mod tests {
 
         let url: &str = &[mockito::SERVER_URL, "/jokes/random"].join("");
         let config = ChuckConfig { url: url.to_string() };
-        let service = ChuckApiServiceImpl { config };
+        let client = HttpClient::new(&HttpClientSettings::default()).unwrap();
+        let service = ChuckApiServiceImpl::new(config, client);
 
-        let res = aw!(service.make_call());
+        let res = aw!(service.make_call(&FlowContext::new("my-flow")));
         match res {
             Ok(r) => assert_eq!(r.value, "blah"),
             Err(e) => assert_eq!(e.to_string(), ""),
         }
     }
+
+    #[test]
+    fn test_make_call_error_status() {
+        let _m = mock("GET", "/jokes/missing")
+            .with_status(404)
+            .with_body("{\"value\":\"not a joke\"}")
+            .create();
+
+        let url: &str = &[mockito::SERVER_URL, "/jokes/missing"].join("");
+        let client = HttpClient::new(&HttpClientSettings::default()).unwrap();
+        let service = ChuckApiServiceImpl::new(ChuckConfig { url: url.to_string() }, client);
+
+        match aw!(service.make_call(&FlowContext::new("my-flow"))) {
+            Ok(_) => assert_eq!("should fail", ""),
+            Err(e) => assert_eq!(e.status(), Some(reqwest::StatusCode::NOT_FOUND)),
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,487 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use deadpool_postgres::Pool;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use proper_rust::database::traced;
+use proper_rust::errors::AppError;
+use proper_rust::flow_logger::FlowContext;
+use proper_rust::monitoring::ErrorTagger;
+
+const MAX_NAME_LENGTH: usize = 100;
+const MAX_PAGE_SIZE: i64 = 100;
+
+const INSERT_ITEM: &str = "INSERT INTO rust_test.grocery_item(name, quantity) VALUES ($1, $2) ON CONFLICT (name) DO NOTHING";
+const UPSERT_ITEM: &str = "INSERT INTO rust_test.grocery_item(name, quantity) VALUES ($1, $2) \
+                           ON CONFLICT (name) DO UPDATE SET quantity = EXCLUDED.quantity \
+                           RETURNING (xmax = 0) AS inserted";
+const SELECT_ITEM: &str = "SELECT name, quantity FROM rust_test.grocery_item WHERE name = $1";
+const UPDATE_QUANTITY: &str = "UPDATE rust_test.grocery_item SET quantity = $2 WHERE name = $1 RETURNING name, quantity";
+const DELETE_ITEM: &str = "DELETE FROM rust_test.grocery_item WHERE name = $1";
+const DELETE_ALL_ITEMS: &str = "DELETE FROM rust_test.grocery_item";
+const COUNT_ITEMS: &str = "SELECT count(*) FROM rust_test.grocery_item WHERE name LIKE $1";
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Item {
+    pub name: String,
+    pub quantity: i32,
+}
+
+impl Item {
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = validate_name(self.name.as_str());
+        problems.extend(validate_quantity(self.quantity));
+        if problems.is_empty() { Ok(()) } else { Err(problems) }
+    }
+}
+
+pub fn validate_name(name: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    if name.trim().is_empty() {
+        problems.push("name must not be empty".to_string());
+    }
+    if name.chars().count() > MAX_NAME_LENGTH {
+        problems.push(format!("name must be at most {} characters", MAX_NAME_LENGTH));
+    }
+    problems
+}
+
+pub fn validate_quantity(quantity: i32) -> Vec<String> {
+    if quantity < 0 {
+        vec!["quantity must not be negative".to_string()]
+    } else {
+        Vec::new()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct QuantityUpdate {
+    pub quantity: i32,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortField {
+    Name,
+    Quantity,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ListQuery {
+    pub offset: i64,
+    pub limit: i64,
+    pub sort: SortField,
+    pub order: SortOrder,
+    pub prefix: Option<String>,
+}
+
+impl Default for ListQuery {
+    fn default() -> Self {
+        ListQuery {
+            offset: 0,
+            limit: 20,
+            sort: SortField::Name,
+            order: SortOrder::Asc,
+            prefix: None,
+        }
+    }
+}
+
+impl ListQuery {
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+        if self.offset < 0 {
+            problems.push("offset must not be negative".to_string());
+        }
+        if self.limit < 1 || self.limit > MAX_PAGE_SIZE {
+            problems.push(format!("limit must be between 1 and {}", MAX_PAGE_SIZE));
+        }
+        if problems.is_empty() { Ok(()) } else { Err(problems) }
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Page {
+    pub items: Vec<Item>,
+    pub total: i64,
+    pub offset: i64,
+    pub limit: i64,
+}
+
+#[derive(Debug)]
+pub enum RepositoryError {
+    Conflict(String),
+    Database(String),
+}
+
+impl fmt::Display for RepositoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepositoryError::Conflict(name) => write!(f, "grocery item {} already exists", name),
+            RepositoryError::Database(e) => write!(f, "grocery database error: {}", e),
+        }
+    }
+}
+
+impl ErrorTagger for RepositoryError {
+    fn error_tag(&self) -> String {
+        match self {
+            RepositoryError::Conflict(_) => "conflict".to_string(),
+            RepositoryError::Database(_) => "database".to_string(),
+        }
+    }
+}
+
+impl From<RepositoryError> for AppError {
+    fn from(e: RepositoryError) -> Self {
+        match e {
+            RepositoryError::Conflict(name) => AppError::Conflict(format!("grocery item {}", name)),
+            RepositoryError::Database(e) => AppError::Database(e),
+        }
+    }
+}
+
+#[async_trait]
+pub trait GroceryRepository {
+    /// Adds a new item, failing with `Conflict` if the name is taken.
+    async fn insert(&self, item: Item) -> Result<(), RepositoryError>;
+    /// Creates or replaces an item, returning whether it was created.
+    async fn upsert(&self, item: Item) -> Result<bool, RepositoryError>;
+    async fn get(&self, name: &str) -> Result<Option<Item>, RepositoryError>;
+    async fn update_quantity(&self, name: &str, quantity: i32) -> Result<Option<Item>, RepositoryError>;
+    /// Returns whether an item was deleted.
+    async fn delete(&self, name: &str) -> Result<bool, RepositoryError>;
+    async fn clear(&self) -> Result<(), RepositoryError>;
+    async fn list(&self, query: &ListQuery) -> Result<Page, RepositoryError>;
+}
+
+pub type SharedGroceryRepository = Arc<dyn GroceryRepository + Send + Sync>;
+
+#[async_trait]
+impl<T: GroceryRepository + Send + Sync + ?Sized> GroceryRepository for Arc<T> {
+    async fn insert(&self, item: Item) -> Result<(), RepositoryError> {
+        (**self).insert(item).await
+    }
+
+    async fn upsert(&self, item: Item) -> Result<bool, RepositoryError> {
+        (**self).upsert(item).await
+    }
+
+    async fn get(&self, name: &str) -> Result<Option<Item>, RepositoryError> {
+        (**self).get(name).await
+    }
+
+    async fn update_quantity(&self, name: &str, quantity: i32) -> Result<Option<Item>, RepositoryError> {
+        (**self).update_quantity(name, quantity).await
+    }
+
+    async fn delete(&self, name: &str) -> Result<bool, RepositoryError> {
+        (**self).delete(name).await
+    }
+
+    async fn clear(&self) -> Result<(), RepositoryError> {
+        (**self).clear().await
+    }
+
+    async fn list(&self, query: &ListQuery) -> Result<Page, RepositoryError> {
+        (**self).list(query).await
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct InMemoryGroceryRepository {
+    grocery_list: Arc<RwLock<HashMap<String, i32>>>,
+}
+
+impl InMemoryGroceryRepository {
+    pub fn new() -> Self {
+        InMemoryGroceryRepository::default()
+    }
+}
+
+#[async_trait]
+impl GroceryRepository for InMemoryGroceryRepository {
+    async fn insert(&self, item: Item) -> Result<(), RepositoryError> {
+        let mut grocery_list = self.grocery_list.write();
+        if grocery_list.contains_key(item.name.as_str()) {
+            return Err(RepositoryError::Conflict(item.name));
+        }
+        grocery_list.insert(item.name, item.quantity);
+        Ok(())
+    }
+
+    async fn upsert(&self, item: Item) -> Result<bool, RepositoryError> {
+        Ok(self.grocery_list.write().insert(item.name, item.quantity).is_none())
+    }
+
+    async fn get(&self, name: &str) -> Result<Option<Item>, RepositoryError> {
+        Ok(self.grocery_list.read().get(name)
+            .map(|quantity| Item { name: name.to_string(), quantity: *quantity }))
+    }
+
+    async fn update_quantity(&self, name: &str, quantity: i32) -> Result<Option<Item>, RepositoryError> {
+        Ok(self.grocery_list.write().get_mut(name)
+            .map(|q| {
+                *q = quantity;
+                Item { name: name.to_string(), quantity }
+            }))
+    }
+
+    async fn delete(&self, name: &str) -> Result<bool, RepositoryError> {
+        Ok(self.grocery_list.write().remove(name).is_some())
+    }
+
+    async fn clear(&self) -> Result<(), RepositoryError> {
+        self.grocery_list.write().clear();
+        Ok(())
+    }
+
+    async fn list(&self, query: &ListQuery) -> Result<Page, RepositoryError> {
+        let prefix = query.prefix.as_deref().unwrap_or("");
+        let mut items: Vec<Item> = self.grocery_list.read().iter()
+            .filter(|(name, _)| name.starts_with(prefix))
+            .map(|(name, quantity)| Item { name: name.clone(), quantity: *quantity })
+            .collect();
+
+        items.sort_by(|a, b| match query.sort {
+            SortField::Name => a.name.cmp(&b.name),
+            SortField::Quantity => a.quantity.cmp(&b.quantity).then_with(|| a.name.cmp(&b.name)),
+        });
+        if query.order == SortOrder::Desc {
+            items.reverse();
+        }
+
+        let total = items.len() as i64;
+        let items = items.into_iter()
+            .skip(query.offset as usize)
+            .take(query.limit as usize)
+            .collect();
+        Ok(Page { items, total, offset: query.offset, limit: query.limit })
+    }
+}
+
+pub struct PostgresGroceryRepository {
+    pool: Pool,
+}
+
+impl PostgresGroceryRepository {
+    pub fn new(pool: Pool) -> Self {
+        PostgresGroceryRepository { pool }
+    }
+
+    async fn client(&self) -> Result<deadpool_postgres::Client, RepositoryError> {
+        self.pool.get().await.map_err(|e| RepositoryError::Database(e.to_string()))
+    }
+}
+
+fn db_error(e: tokio_postgres::Error) -> RepositoryError {
+    RepositoryError::Database(e.to_string())
+}
+
+/// Runs `statement` inside a client span of the flow the repository is called from.
+async fn traced_query<F, T>(statement: &str, f: impl FnOnce() -> F) -> Result<T, RepositoryError>
+    where
+        F: Future<Output=Result<T, tokio_postgres::Error>>,
+{
+    let fc = FlowContext::current().unwrap_or_else(|| FlowContext::new("grocery-repository"));
+    traced(&fc, statement, f).await.map_err(db_error)
+}
+
+fn like_prefix(prefix: &str) -> String {
+    let escaped = prefix.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    format!("{}%", escaped)
+}
+
+#[async_trait]
+impl GroceryRepository for PostgresGroceryRepository {
+    async fn insert(&self, item: Item) -> Result<(), RepositoryError> {
+        let client = self.client().await?;
+        let inserted = traced_query(INSERT_ITEM, || async {
+            let stmt = client.prepare_cached(INSERT_ITEM).await?;
+            client.execute(&stmt, &[&item.name, &item.quantity]).await
+        }).await?;
+        if inserted == 0 {
+            return Err(RepositoryError::Conflict(item.name));
+        }
+        Ok(())
+    }
+
+    async fn upsert(&self, item: Item) -> Result<bool, RepositoryError> {
+        let client = self.client().await?;
+        let row = traced_query(UPSERT_ITEM, || async {
+            let stmt = client.prepare_cached(UPSERT_ITEM).await?;
+            client.query_one(&stmt, &[&item.name, &item.quantity]).await
+        }).await?;
+        Ok(row.get(0))
+    }
+
+    async fn get(&self, name: &str) -> Result<Option<Item>, RepositoryError> {
+        let client = self.client().await?;
+        let row = traced_query(SELECT_ITEM, || async {
+            let stmt = client.prepare_cached(SELECT_ITEM).await?;
+            client.query_opt(&stmt, &[&name]).await
+        }).await?;
+        Ok(row.map(|row| Item { name: row.get(0), quantity: row.get(1) }))
+    }
+
+    async fn update_quantity(&self, name: &str, quantity: i32) -> Result<Option<Item>, RepositoryError> {
+        let client = self.client().await?;
+        let row = traced_query(UPDATE_QUANTITY, || async {
+            let stmt = client.prepare_cached(UPDATE_QUANTITY).await?;
+            client.query_opt(&stmt, &[&name, &quantity]).await
+        }).await?;
+        Ok(row.map(|row| Item { name: row.get(0), quantity: row.get(1) }))
+    }
+
+    async fn delete(&self, name: &str) -> Result<bool, RepositoryError> {
+        let client = self.client().await?;
+        let deleted = traced_query(DELETE_ITEM, || async {
+            let stmt = client.prepare_cached(DELETE_ITEM).await?;
+            client.execute(&stmt, &[&name]).await
+        }).await?;
+        Ok(deleted > 0)
+    }
+
+    async fn clear(&self) -> Result<(), RepositoryError> {
+        let client = self.client().await?;
+        traced_query(DELETE_ALL_ITEMS, || client.execute(DELETE_ALL_ITEMS, &[])).await?;
+        Ok(())
+    }
+
+    async fn list(&self, query: &ListQuery) -> Result<Page, RepositoryError> {
+        let client = self.client().await?;
+        let prefix = like_prefix(query.prefix.as_deref().unwrap_or(""));
+
+        let total: i64 = traced_query(COUNT_ITEMS, || async {
+            let stmt = client.prepare_cached(COUNT_ITEMS).await?;
+            client.query_one(&stmt, &[&prefix]).await
+        }).await?.get(0);
+
+        // column and direction come from enums, never from the raw query string
+        let order_by = match (query.sort, query.order) {
+            (SortField::Name, SortOrder::Asc) => "name ASC",
+            (SortField::Name, SortOrder::Desc) => "name DESC",
+            (SortField::Quantity, SortOrder::Asc) => "quantity ASC, name ASC",
+            (SortField::Quantity, SortOrder::Desc) => "quantity DESC, name DESC",
+        };
+        let select = format!(
+            "SELECT name, quantity FROM rust_test.grocery_item WHERE name LIKE $1 ORDER BY {} LIMIT $2 OFFSET $3",
+            order_by
+        );
+        let rows = traced_query(select.as_str(), || async {
+            let stmt = client.prepare_cached(select.as_str()).await?;
+            client.query(&stmt, &[&prefix, &query.limit, &query.offset]).await
+        }).await?;
+
+        Ok(Page {
+            items: rows.iter().map(|row| Item { name: row.get(0), quantity: row.get(1) }).collect(),
+            total,
+            offset: query.offset,
+            limit: query.limit,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proper_rust::database::create_pool;
+    use proper_rust::migrations;
+    use proper_rust::settings::Database;
+
+    use super::*;
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    fn item(name: &str, quantity: i32) -> Item {
+        Item { name: name.to_string(), quantity }
+    }
+
+    #[test]
+    fn test_item_validation() {
+        assert!(item("milk", 0).validate().is_ok());
+        assert_eq!(item(" ", -1).validate(), Err(vec![
+            "name must not be empty".to_string(),
+            "quantity must not be negative".to_string(),
+        ]));
+    }
+
+    #[test]
+    fn test_in_memory_crud() {
+        let repo = InMemoryGroceryRepository::new();
+        aw!(repo.insert(item("milk", 1))).unwrap();
+        assert!(matches!(aw!(repo.insert(item("milk", 2))), Err(RepositoryError::Conflict(_))));
+        assert!(!aw!(repo.upsert(item("milk", 3))).unwrap());
+        assert_eq!(aw!(repo.get("milk")).unwrap(), Some(item("milk", 3)));
+        assert_eq!(aw!(repo.update_quantity("milk", 5)).unwrap(), Some(item("milk", 5)));
+        assert_eq!(aw!(repo.update_quantity("bread", 5)).unwrap(), None);
+        assert!(aw!(repo.delete("milk")).unwrap());
+        assert!(!aw!(repo.delete("milk")).unwrap());
+    }
+
+    #[test]
+    fn test_in_memory_list_filters_sorts_and_pages() {
+        let repo = InMemoryGroceryRepository::new();
+        for (name, quantity) in &[("apple", 3), ("apricot", 1), ("avocado", 2), ("banana", 6)] {
+            aw!(repo.insert(item(name, *quantity))).unwrap();
+        }
+
+        let page = aw!(repo.list(&ListQuery {
+            offset: 1,
+            limit: 1,
+            sort: SortField::Quantity,
+            order: SortOrder::Desc,
+            prefix: Some("a".to_string()),
+        })).unwrap();
+
+        assert_eq!(page, Page { items: vec![item("avocado", 2)], total: 3, offset: 1, limit: 1 });
+    }
+
+    #[test]
+    fn test_postgres_crud_and_list() {
+        let pool = create_pool(&Database {
+            url: "postgresql://localhost/create_drop".into(),
+            password: "asdf123".into(),
+            ..Database::default()
+        }).unwrap();
+        aw!(migrations::run(&pool, &migrations::embedded().unwrap())).unwrap();
+
+        let repo = PostgresGroceryRepository::new(pool);
+        aw!(repo.delete("grocery_test_flour")).unwrap();
+        aw!(repo.delete("grocery_test_fig")).unwrap();
+
+        aw!(repo.insert(item("grocery_test_flour", 1))).unwrap();
+        assert!(matches!(aw!(repo.insert(item("grocery_test_flour", 1))), Err(RepositoryError::Conflict(_))));
+        assert!(aw!(repo.upsert(item("grocery_test_fig", 7))).unwrap());
+        assert!(!aw!(repo.upsert(item("grocery_test_flour", 4))).unwrap());
+        assert_eq!(aw!(repo.update_quantity("grocery_test_fig", 8)).unwrap(), Some(item("grocery_test_fig", 8)));
+
+        let page = aw!(repo.list(&ListQuery {
+            sort: SortField::Quantity,
+            prefix: Some("grocery_test_f".to_string()),
+            ..ListQuery::default()
+        })).unwrap();
+        assert_eq!(page.items, vec![item("grocery_test_flour", 4), item("grocery_test_fig", 8)]);
+        assert_eq!(page.total, 2);
+
+        assert!(aw!(repo.delete("grocery_test_flour")).unwrap());
+        assert_eq!(aw!(repo.get("grocery_test_flour")).unwrap(), None);
+        aw!(repo.delete("grocery_test_fig")).unwrap();
+    }
+}
@@ -1,108 +1,176 @@
-use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
 use deadpool_postgres::Pool;
 use lazy_static::lazy_static;
-use parking_lot::RwLock;
-use serde::{Deserialize, Serialize};
-use warp::{Filter, http};
+use percent_encoding::percent_decode_str;
+use serde::de::DeserializeOwned;
+use warp::{Filter, http, Reply};
+use warp::reply::Response;
 
+use proper_rust::flow_logger;
 use proper_rust::flow_logger::{FlowContext, FlowLogger};
-use proper_rust::monitoring::{ErrorTagger, timed};
+use proper_rust::health::{HttpHealthCheck, PostgresHealthCheck};
+use proper_rust::http_client::HttpClient;
+use proper_rust::http_metrics::route;
+use proper_rust::circuit_breaker::CircuitBreaker;
+use proper_rust::database::traced;
+use proper_rust::errors::AppError;
+use proper_rust::monitoring::timed;
+use proper_rust::settings::{ConfigSource, GroceryBackend};
+use proper_rust::StartupError;
+use proper_rust::telemetry;
 
 use crate::api::*;
+use crate::grocery::*;
 
 mod api;
-mod proper_rust;
+mod grocery;
 
-type Items = HashMap<String, i32>;
+const CHUCK_URL: &str = "https://api.chucknorris.io/jokes/random";
+const CHUCK_HEALTH_URL: &str = "https://api.chucknorris.io/";
 
 lazy_static! {
-    static ref LOG: FlowLogger = FlowLogger::new("app::backend");
+    static ref LOG: FlowLogger = flow_logger!("app::backend");
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-struct Item {
-    name: String,
-    quantity: i32,
-}
-
-#[derive(Clone)]
-struct Store {
-    grocery_list: Arc<RwLock<Items>>,
-}
-
-impl Store {
-    fn new() -> Self {
-        Store {
-            grocery_list: Arc::new(RwLock::new(HashMap::new())),
-        }
-    }
+fn not_found(name: &str) -> warp::Rejection {
+    AppError::NotFound(format!("grocery item {}", name)).into()
 }
 
-
 async fn add_grocery_list_item(
     item: Item,
-    store: Store,
-    _fc: FlowContext,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    store.grocery_list.write().insert(item.name, item.quantity);
+    repo: impl GroceryRepository,
+    fc: FlowContext,
+) -> Result<Response, warp::Rejection> {
+    item.validate().map_err(AppError::Validation)?;
+    repo.insert(item.clone()).await.map_err(AppError::from)?;
 
-    Ok(warp::reply::with_status(
-        "Added items to the grocery list",
-        http::StatusCode::CREATED,
-    ))
+    LOG.info(&fc, format!("Added grocery item {}", item.name).as_str());
+    Ok(warp::reply::with_status(warp::reply::json(&item), http::StatusCode::CREATED).into_response())
 }
 
-fn json_body() -> impl Filter<Extract=(Item, ), Error=warp::Rejection> + Clone {
+fn json_body<T: DeserializeOwned + Send>() -> impl Filter<Extract=(T, ), Error=warp::Rejection> + Clone {
     // When accepting a body, we want a JSON body
     // (and to reject huge payloads)...
     warp::body::content_length_limit(1024 * 16).and(warp::body::json())
 }
 
+fn item_name() -> impl Filter<Extract=(String, ), Error=warp::Rejection> + Copy {
+    warp::path::param::<String>().and_then(|raw: String| async move {
+        percent_decode_str(raw.as_str())
+            .decode_utf8()
+            .map(|name| name.to_string())
+            .map_err(|_| warp::reject::not_found())
+    })
+}
+
 async fn get_grocery_list(
-    store: Store,
+    query: ListQuery,
+    repo: impl GroceryRepository,
     fc: FlowContext,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    timed("get_grocery_list", || {
-        async {
-            let mut result = HashMap::new();
-            let r = store.grocery_list.read();
-
-            for (key, value) in r.iter() {
-                result.insert(key, value);
-            }
+) -> Result<Response, warp::Rejection> {
+    timed("get_grocery_list", &fc, |fc| {
+        async move {
+            query.validate().map_err(AppError::Validation)?;
+            let page = repo.list(&query).await.map_err(AppError::from)?;
 
-            LOG.info(&fc, "Fetched grocery list");
+            LOG.info_kv(&fc, "Fetched grocery list", &[("count", page.items.len() as i64), ("total", page.total)]);
 
             Ok(warp::reply::json(
-                &result
-            ))
+                &page
+            ).into_response())
         }
     }).await
 }
 
-impl ErrorTagger for warp::Rejection {
-    fn error_tag(&self) -> String {
-        match self {
-            _ => "rejection".to_string()
+async fn get_grocery_item(
+    name: String,
+    repo: impl GroceryRepository,
+    _fc: FlowContext,
+) -> Result<Response, warp::Rejection> {
+    match repo.get(name.as_str()).await.map_err(AppError::from)? {
+        Some(item) => Ok(warp::reply::json(&item).into_response()),
+        None => Err(not_found(name.as_str())),
+    }
+}
+
+async fn put_grocery_item(
+    name: String,
+    update: QuantityUpdate,
+    repo: impl GroceryRepository,
+    fc: FlowContext,
+) -> Result<Response, warp::Rejection> {
+    let item = Item { name, quantity: update.quantity };
+    item.validate().map_err(AppError::Validation)?;
+    let created = repo.upsert(item.clone()).await.map_err(AppError::from)?;
+
+    LOG.info(&fc, format!("Stored grocery item {}", item.name).as_str());
+    let status = if created { http::StatusCode::CREATED } else { http::StatusCode::OK };
+    Ok(warp::reply::with_status(warp::reply::json(&item), status).into_response())
+}
+
+async fn patch_grocery_item(
+    name: String,
+    update: QuantityUpdate,
+    repo: impl GroceryRepository,
+    fc: FlowContext,
+) -> Result<Response, warp::Rejection> {
+    let problems = validate_quantity(update.quantity);
+    if !problems.is_empty() {
+        return Err(AppError::Validation(problems).into());
+    }
+
+    match repo.update_quantity(name.as_str(), update.quantity).await.map_err(AppError::from)? {
+        Some(item) => {
+            LOG.info(&fc, format!("Updated grocery item {}", item.name).as_str());
+            Ok(warp::reply::json(&item).into_response())
         }
+        None => Err(not_found(name.as_str())),
     }
 }
 
-async fn chuck(pool: Pool, chuck_api: impl ChuckApiService, fc: FlowContext) -> Result<impl warp::Reply, warp::Rejection> {
-    timed("chuck", || {
-        async {
+async fn delete_grocery_item(
+    name: String,
+    repo: impl GroceryRepository,
+    fc: FlowContext,
+) -> Result<Response, warp::Rejection> {
+    if !repo.delete(name.as_str()).await.map_err(AppError::from)? {
+        return Err(not_found(name.as_str()));
+    }
+
+    LOG.info(&fc, format!("Deleted grocery item {}", name).as_str());
+    Ok(http::StatusCode::NO_CONTENT.into_response())
+}
+
+async fn clear_grocery_list(
+    repo: impl GroceryRepository,
+    fc: FlowContext,
+) -> Result<Response, warp::Rejection> {
+    repo.clear().await.map_err(AppError::from)?;
+
+    LOG.info(&fc, "Cleared grocery list");
+    Ok(http::StatusCode::NO_CONTENT.into_response())
+}
+
+async fn chuck(
+    pool: Option<Pool>,
+    chuck_api: impl ChuckApiService,
+    breaker: CircuitBreaker,
+    fc: FlowContext,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    timed("chuck", &fc, |fc| {
+        async move {
             LOG.info(&fc, "making api call");
 
-            let res = chuck_api.make_call().await;
+            let res = breaker.call(&fc, || chuck_api.make_call(&fc)).await;
 
-            let res2 = match res {
-                Ok(a) => Ok(a),
-                Err(_) => Err(warp::reject()),
-            }?;
+            let res2 = res.map_err(AppError::from)?;
 
-            write_chuck(pool, &res2).await;
+            if let Some(pool) = pool {
+                write_chuck(pool, &fc, &res2).await?;
+            }
 
             Ok(warp::reply::json(
                 &res2
@@ -124,61 +192,188 @@ async fn db_run(pool: &Pool) {
     }
 }
 
-async fn write_chuck(pool: Pool, chuck: &Chuck) {
-    let client = pool.get().await.unwrap();
-    let stmt = client.prepare_cached("INSERT INTO rust_test.chuck(value) VALUES ($1)").await.unwrap();
-    let _rows = client.query(&stmt, &[&chuck.value]).await.unwrap();
+async fn write_chuck(pool: Pool, fc: &FlowContext, chuck: &Chuck) -> Result<(), AppError> {
+    const INSERT_CHUCK: &str = "INSERT INTO rust_test.chuck(value) VALUES ($1)";
+
+    let database_error = |e: &dyn std::fmt::Display| AppError::Database(e.to_string());
+    let client = pool.get().await.map_err(|e| database_error(&e))?;
+    traced(fc, INSERT_CHUCK, || async {
+        let stmt = client.prepare_cached(INSERT_CHUCK).await?;
+        client.execute(&stmt, &[&chuck.value]).await
+    }).await.map_err(|e| database_error(&e))?;
+    Ok(())
+}
+
+/// `--config-dir <dir>` or `--config-dir=<dir>` from the command line.
+fn config_dir_arg(mut args: impl Iterator<Item=String>) -> Option<PathBuf> {
+    while let Some(arg) = args.next() {
+        if arg == "--config-dir" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(dir) = arg.strip_prefix("--config-dir=") {
+            return Some(PathBuf::from(dir));
+        }
+    }
+    None
+}
+
+fn startup_failed(e: StartupError) -> ! {
+    eprintln!("{}", e);
+    std::process::exit(e.exit_code())
 }
 
 #[tokio::main]
 async fn main() {
-    let (_config, pool_opt) = proper_rust::setup();
-    let pool = pool_opt.unwrap();
+    let source = ConfigSource::from_env(config_dir_arg(std::env::args().skip(1)));
+    let (config, pool) = proper_rust::setup(&source).await.unwrap_or_else(|e| startup_failed(e));
+    let http_client = HttpClient::new(&config.http_client)
+        .unwrap_or_else(|e| startup_failed(StartupError::HttpClient(e)));
 
-    db_run(&pool).await;
+    if let Some(pool) = &pool {
+        db_run(pool).await;
+    }
 
-    let store = Store::new();
-    let store_filter = warp::any().map(move || {
-        store.clone()
+    let grocery_repo: SharedGroceryRepository = match (&config.groceries.backend, &pool) {
+        (GroceryBackend::Memory, _) => Arc::new(InMemoryGroceryRepository::new()),
+        (GroceryBackend::Postgres, Some(pool)) => Arc::new(PostgresGroceryRepository::new(pool.clone())),
+        (GroceryBackend::Postgres, None) => unreachable!("Settings::check requires database.enabled for the postgres backend"),
+    };
+    let grocery_repo_filter = warp::any().map(move || {
+        grocery_repo.clone()
     });
 
+    let health = match &pool {
+        Some(pool) => proper_rust::Health::new().with_check(PostgresHealthCheck::new(pool.clone())),
+        None => proper_rust::Health::new(),
+    };
+    let health = health
+        .with_check(HttpHealthCheck::new("chuck-api", CHUCK_HEALTH_URL, false, http_client.clone()))
+        .with_check_timeout(Duration::from_millis(config.monitoring.check_timeout_ms));
+
+    let shutdown_pool = pool.clone();
     let pool_filter = warp::any().map(move || {
         pool.clone()
     });
 
+    let chuck_breaker = CircuitBreaker::new("chuck-api", &config.circuit_breaker);
+    let chuck_breaker_filter = warp::any().map(move || chuck_breaker.clone());
     let chuck_api_service_filter = warp::any().map(move || {
-        ChuckApiServiceImpl::new(ChuckConfig { url: "https://api.chucknorris.io/jokes/random".to_string() })
+        ChuckApiServiceImpl::new(ChuckConfig { url: CHUCK_URL.to_string() }, http_client.clone())
     });
 
-    let add_items = warp::post()
-        .and(warp::path("v1"))
-        .and(warp::path("groceries"))
-        .and(warp::path::end())
-        .and(json_body())
-        .and(store_filter.clone())
-        .and(FlowContext::extract_flow_context())
-        .and_then(add_grocery_list_item);
-
-    let get_items = warp::get()
-        .and(warp::path("v1"))
-        .and(warp::path("groceries"))
-        .and(warp::path::end())
-        .and(store_filter.clone())
-        .and(FlowContext::extract_flow_context())
-        .and_then(get_grocery_list);
-
-    let chuck = warp::get()
-        .and(warp::path("v1"))
-        .and(warp::path("chuck"))
-        .and(warp::path::end())
-        .and(pool_filter.clone())
-        .and(chuck_api_service_filter.clone())
-        .and(FlowContext::extract_flow_context())
-        .and_then(chuck);
-
-    let routes = add_items.or(get_items).or(chuck);
+    let groceries = warp::path("v1")
+        .and(warp::path("groceries"));
+    let grocery_item = groceries
+        .and(item_name())
+        .and(warp::path::end());
+
+    let add_items = route(
+        "/v1/groceries",
+        warp::post()
+            .and(groceries)
+            .and(warp::path::end())
+            .and(json_body())
+            .and(grocery_repo_filter.clone())
+            .and(FlowContext::extract_flow_context())
+            .and_then(add_grocery_list_item),
+    );
+
+    let get_items = route(
+        "/v1/groceries",
+        warp::get()
+            .and(groceries)
+            .and(warp::path::end())
+            .and(warp::query::<ListQuery>())
+            .and(grocery_repo_filter.clone())
+            .and(FlowContext::extract_flow_context())
+            .and_then(get_grocery_list),
+    );
+
+    let clear_items = route(
+        "/v1/groceries",
+        warp::delete()
+            .and(groceries)
+            .and(warp::path::end())
+            .and(grocery_repo_filter.clone())
+            .and(FlowContext::extract_flow_context())
+            .and_then(clear_grocery_list),
+    );
+
+    let get_item = route(
+        "/v1/groceries/{name}",
+        warp::get()
+            .and(grocery_item)
+            .and(grocery_repo_filter.clone())
+            .and(FlowContext::extract_flow_context())
+            .and_then(get_grocery_item),
+    );
+
+    let put_item = route(
+        "/v1/groceries/{name}",
+        warp::put()
+            .and(grocery_item)
+            .and(json_body())
+            .and(grocery_repo_filter.clone())
+            .and(FlowContext::extract_flow_context())
+            .and_then(put_grocery_item),
+    );
+
+    let patch_item = route(
+        "/v1/groceries/{name}",
+        warp::patch()
+            .and(grocery_item)
+            .and(json_body())
+            .and(grocery_repo_filter.clone())
+            .and(FlowContext::extract_flow_context())
+            .and_then(patch_grocery_item),
+    );
+
+    let delete_item = route(
+        "/v1/groceries/{name}",
+        warp::delete()
+            .and(grocery_item)
+            .and(grocery_repo_filter.clone())
+            .and(FlowContext::extract_flow_context())
+            .and_then(delete_grocery_item),
+    );
+
+    let chuck = route(
+        "/v1/chuck",
+        warp::get()
+            .and(warp::path("v1"))
+            .and(warp::path("chuck"))
+            .and(warp::path::end())
+            .and(pool_filter.clone())
+            .and(chuck_api_service_filter)
+            .and(chuck_breaker_filter)
+            .and(FlowContext::extract_flow_context())
+            .and_then(chuck),
+    );
+
+    let routes = add_items
+        .or(get_items)
+        .or(clear_items)
+        .or(get_item)
+        .or(put_item)
+        .or(patch_item)
+        .or(delete_item)
+        .or(chuck);
+
+    let shutdown = proper_rust::Shutdown::new();
+    if let Some(shutdown_pool) = shutdown_pool {
+        shutdown.on_shutdown("close-database-pool", move || async move {
+            shutdown_pool.close();
+        });
+    }
+    shutdown.on_shutdown("flush-spans", || async {
+        telemetry::flush().await;
+    });
+    shutdown.on_shutdown("flush-logs", || async {
+        log::logger().flush();
+    });
 
-    proper_rust::start_server(routes).await;
+    proper_rust::start_server(routes, &config.api, &config.monitoring, health, shutdown).await
+        .unwrap_or_else(|e| startup_failed(e));
 }
 
 
@@ -189,8 +384,10 @@ mod tests {
     use warp::hyper::body::HttpBody;
     use warp::Reply;
 
-    use crate::proper_rust::database::create_pool;
-    use crate::proper_rust::settings::Database;
+    use proper_rust::database::create_pool;
+    use proper_rust::errors::{problem_response, rejection_status};
+    use proper_rust::migrations;
+    use proper_rust::settings::{CircuitBreakerSettings, Database};
 
     use super::*;
 
@@ -204,7 +401,7 @@ mod tests {
 
     #[async_trait]
     impl ChuckApiService for MockChuckApiService {
-        async fn make_call(&self) -> Result<Chuck, Error> {
+        async fn make_call(&self, _fc: &FlowContext) -> Result<Chuck, Error> {
             Ok(Chuck { value: "blah".to_string() })
         }
     }
@@ -214,17 +411,15 @@ mod tests {
         let mock_chuck = MockChuckApiService;
 
         let pool = create_pool(&Database {
-            enabled: false,
-            url: "postgresql://localhost/create_drop".to_string(),
-            username: "postgres".to_string(),
-            password: "asdf123".to_string(),
-            port: 5432,
-        });
+            url: "postgresql://localhost/create_drop".into(),
+            password: "asdf123".into(),
+            ..Database::default()
+        }).unwrap();
+        aw!(migrations::run(&pool, &migrations::embedded().unwrap())).unwrap();
 
-        let fc = FlowContext {
-            flow_id: "my-flow".to_string()
-        };
-        let res = aw!(chuck(pool, mock_chuck, fc));
+        let fc = FlowContext::new("my-flow");
+        let breaker = CircuitBreaker::new("chuck-test", &CircuitBreakerSettings::default());
+        let res = aw!(chuck(Some(pool), mock_chuck, breaker.clone(), fc.clone()));
 
         match res {
             Ok(r) => {
@@ -233,13 +428,92 @@ mod tests {
             },
             Err(_) => assert_eq!("should not reject", ""),
         }
+
+        match aw!(chuck(None, MockChuckApiService, breaker, fc)) {
+            Ok(r) => assert_eq!(warp_reply(r), "{\"value\":\"blah\"}"),
+            Err(_) => assert_eq!("should not reject", ""),
+        }
+    }
+
+    #[test]
+    fn config_dir_from_args() {
+        let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<_>>().into_iter();
+
+        assert_eq!(config_dir_arg(args(&["--config-dir", "/etc/app"])), Some(PathBuf::from("/etc/app")));
+        assert_eq!(config_dir_arg(args(&["-v", "--config-dir=conf"])), Some(PathBuf::from("conf")));
+        assert_eq!(config_dir_arg(args(&["--other"])), None);
+    }
+
+    fn status(r: Result<Response, warp::Rejection>) -> http::StatusCode {
+        match r {
+            Ok(r) => r.status(),
+            Err(rejection) => rejection_status(&rejection),
+        }
+    }
+
+    fn milk(quantity: i32) -> Item {
+        Item { name: "milk".to_string(), quantity }
+    }
+
+    #[test]
+    fn test_grocery_list() {
+        let repo = InMemoryGroceryRepository::new();
+        let fc = FlowContext::new("my-flow");
+
+        assert_eq!(status(aw!(add_grocery_list_item(milk(2), repo.clone(), fc.clone()))), http::StatusCode::CREATED);
+        assert_eq!(status(aw!(add_grocery_list_item(milk(3), repo.clone(), fc.clone()))), http::StatusCode::CONFLICT);
+
+        match aw!(get_grocery_list(ListQuery::default(), repo, fc)) {
+            Ok(r) => assert_eq!(warp_reply(r), "{\"items\":[{\"name\":\"milk\",\"quantity\":2}],\"total\":1,\"offset\":0,\"limit\":20}"),
+            Err(_) => assert_eq!("should not reject", ""),
+        }
+    }
+
+    #[test]
+    fn test_grocery_item_lifecycle() {
+        let repo = InMemoryGroceryRepository::new();
+        let fc = FlowContext::new("my-flow");
+        let name = || "milk".to_string();
+
+        assert_eq!(status(aw!(get_grocery_item(name(), repo.clone(), fc.clone()))), http::StatusCode::NOT_FOUND);
+        assert_eq!(status(aw!(patch_grocery_item(name(), QuantityUpdate { quantity: 1 }, repo.clone(), fc.clone()))), http::StatusCode::NOT_FOUND);
+        assert_eq!(status(aw!(put_grocery_item(name(), QuantityUpdate { quantity: 1 }, repo.clone(), fc.clone()))), http::StatusCode::CREATED);
+        assert_eq!(status(aw!(put_grocery_item(name(), QuantityUpdate { quantity: 2 }, repo.clone(), fc.clone()))), http::StatusCode::OK);
+        assert_eq!(status(aw!(patch_grocery_item(name(), QuantityUpdate { quantity: 4 }, repo.clone(), fc.clone()))), http::StatusCode::OK);
+
+        match aw!(get_grocery_item(name(), repo.clone(), fc.clone())) {
+            Ok(r) => assert_eq!(warp_reply(r), "{\"name\":\"milk\",\"quantity\":4}"),
+            Err(_) => assert_eq!("should not reject", ""),
+        }
+
+        assert_eq!(status(aw!(delete_grocery_item(name(), repo.clone(), fc.clone()))), http::StatusCode::NO_CONTENT);
+        assert_eq!(status(aw!(delete_grocery_item(name(), repo.clone(), fc.clone()))), http::StatusCode::NOT_FOUND);
+        assert_eq!(status(aw!(clear_grocery_list(repo, fc))), http::StatusCode::NO_CONTENT);
+    }
+
+    #[test]
+    fn test_grocery_validation() {
+        let repo = InMemoryGroceryRepository::new();
+        let fc = FlowContext::new("my-flow");
+
+        match aw!(add_grocery_list_item(Item { name: "".to_string(), quantity: -1 }, repo.clone(), fc.clone())) {
+            Ok(_) => assert_eq!("should reject", ""),
+            Err(rejection) => {
+                let r = problem_response(&fc, &rejection);
+                assert_eq!(r.status(), http::StatusCode::BAD_REQUEST);
+                assert_eq!(warp_reply(r), "{\"status\":400,\"title\":\"Bad Request\",\"detail\":\"invalid request\",\"errors\":[\"name must not be empty\",\"quantity must not be negative\"],\"flow-id\":\"my-flow\"}");
+            }
+        }
+
+        let query = ListQuery { limit: 0, ..ListQuery::default() };
+        assert_eq!(status(aw!(get_grocery_list(query, repo, fc))), http::StatusCode::BAD_REQUEST);
     }
 
     fn warp_reply(r: impl Reply) -> String {
         let response = r.into_response();
         let body = aw!(response.into_body().data());
         let b = body.unwrap().unwrap();
-        String::from_utf8_lossy(&b.to_vec()).to_string()
+        String::from_utf8_lossy(&b).to_string()
     }
 
 }
@@ -0,0 +1,317 @@
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+
+use crate::flow_logger;
+use crate::flow_logger::{FlowContext, FlowLogger};
+use crate::monitoring::{counter_vec, ErrorTagger, gauge_vec};
+use crate::settings::CircuitBreakerSettings;
+
+lazy_static! {
+    static ref LOG: FlowLogger = flow_logger!("proper_rust::circuit_breaker");
+}
+
+/// Tag of calls rejected because the circuit is open.
+pub const CIRCUIT_OPEN_TAG: &str = "circuit-open";
+
+const STATES: [CircuitState; 3] = [CircuitState::Closed, CircuitState::Open, CircuitState::HalfOpen];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl CircuitState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half-open",
+        }
+    }
+}
+
+/// Either the circuit was open and the call was not made, or the call itself failed.
+#[derive(Debug)]
+pub enum CircuitError<E> {
+    Open(String),
+    Inner(E),
+}
+
+impl<E: fmt::Display> fmt::Display for CircuitError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CircuitError::Open(name) => write!(f, "circuit {} is open", name),
+            CircuitError::Inner(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for CircuitError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CircuitError::Open(_) => None,
+            CircuitError::Inner(e) => Some(e),
+        }
+    }
+}
+
+impl<E: ErrorTagger> ErrorTagger for CircuitError<E> {
+    fn error_tag(&self) -> String {
+        match self {
+            CircuitError::Open(_) => CIRCUIT_OPEN_TAG.to_string(),
+            CircuitError::Inner(e) => e.error_tag(),
+        }
+    }
+}
+
+struct Circuit {
+    state: CircuitState,
+    failures: u32,
+    opened_at: Instant,
+    trials: u32,
+}
+
+/// Stops calling a failing dependency. After `failure_threshold` consecutive failures the
+/// circuit opens and calls fail fast; after `cool_down_ms` a few trial calls are let
+/// through (half-open), closing the circuit on success or opening it again on failure.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    name: String,
+    failure_threshold: u32,
+    cool_down: Duration,
+    half_open_max_calls: u32,
+    circuit: Arc<Mutex<Circuit>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &str, settings: &CircuitBreakerSettings) -> Self {
+        let breaker = CircuitBreaker {
+            name: name.to_string(),
+            failure_threshold: settings.failure_threshold.max(1),
+            cool_down: Duration::from_millis(settings.cool_down_ms),
+            half_open_max_calls: settings.half_open_max_calls.max(1),
+            circuit: Arc::new(Mutex::new(Circuit {
+                state: CircuitState::Closed,
+                failures: 0,
+                opened_at: Instant::now(),
+                trials: 0,
+            })),
+        };
+        breaker.publish_state(CircuitState::Closed);
+        breaker
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.circuit.lock().state
+    }
+
+    /// Runs `f` unless the circuit is open. Every error returned by `f` counts as a failure.
+    pub async fn call<F, T, E>(&self, fc: &FlowContext, f: impl FnOnce() -> F) -> Result<T, CircuitError<E>>
+        where
+            F: Future<Output=Result<T, E>>,
+    {
+        let permit = match self.acquire(fc) {
+            Some(permit) => permit,
+            None => {
+                counter_vec("circuit_breaker_rejected_total", &["name"])
+                    .with_label_values(&[self.name.as_str()])
+                    .inc();
+                return Err(CircuitError::Open(self.name.clone()));
+            }
+        };
+
+        let res = f().await;
+        permit.record(res.is_ok());
+        res.map_err(CircuitError::Inner)
+    }
+
+    fn acquire<'a>(&'a self, fc: &'a FlowContext) -> Option<Permit<'a>> {
+        let mut circuit = self.circuit.lock();
+        if circuit.state == CircuitState::Open && circuit.opened_at.elapsed() >= self.cool_down {
+            self.transition(fc, &mut circuit, CircuitState::HalfOpen);
+        }
+        let trial = match circuit.state {
+            CircuitState::Closed => false,
+            CircuitState::Open => return None,
+            CircuitState::HalfOpen if circuit.trials < self.half_open_max_calls => {
+                circuit.trials += 1;
+                true
+            }
+            CircuitState::HalfOpen => return None,
+        };
+        Some(Permit { breaker: self, fc, trial })
+    }
+
+    fn record(&self, fc: &FlowContext, success: bool) {
+        let mut circuit = self.circuit.lock();
+        match (circuit.state, success) {
+            (CircuitState::HalfOpen, true) => self.transition(fc, &mut circuit, CircuitState::Closed),
+            (CircuitState::HalfOpen, false) => self.transition(fc, &mut circuit, CircuitState::Open),
+            (CircuitState::Closed, true) => circuit.failures = 0,
+            (CircuitState::Closed, false) => {
+                circuit.failures += 1;
+                if circuit.failures >= self.failure_threshold {
+                    self.transition(fc, &mut circuit, CircuitState::Open);
+                }
+            }
+            // Calls admitted before the circuit opened; their outcome no longer matters.
+            (CircuitState::Open, _) => {}
+        }
+    }
+
+    /// A trial call was dropped before it finished: open again rather than wait for it forever.
+    fn abandon(&self, fc: &FlowContext) {
+        let mut circuit = self.circuit.lock();
+        if circuit.state == CircuitState::HalfOpen {
+            self.transition(fc, &mut circuit, CircuitState::Open);
+        }
+    }
+
+    fn transition(&self, fc: &FlowContext, circuit: &mut Circuit, to: CircuitState) {
+        let from = circuit.state;
+        circuit.state = to;
+        circuit.failures = 0;
+        circuit.trials = 0;
+        if to == CircuitState::Open {
+            circuit.opened_at = Instant::now();
+        }
+
+        counter_vec("circuit_breaker_transitions_total", &["name", "from", "to"])
+            .with_label_values(&[self.name.as_str(), from.as_str(), to.as_str()])
+            .inc();
+        self.publish_state(to);
+
+        let message = format!("circuit {} changed from {} to {}", self.name, from.as_str(), to.as_str());
+        match to {
+            CircuitState::Open => LOG.warn(fc, message.as_str()),
+            _ => LOG.info(fc, message.as_str()),
+        }
+    }
+
+    /// Sets `circuit_breaker_state` to 1 for the current state and 0 for the others.
+    fn publish_state(&self, current: CircuitState) {
+        let gauge = gauge_vec("circuit_breaker_state", &["name", "state"]);
+        for state in STATES.iter() {
+            let value = if *state == current { 1.0 } else { 0.0 };
+            gauge.with_label_values(&[self.name.as_str(), state.as_str()]).set(value);
+        }
+    }
+}
+
+/// A call let through by [`CircuitBreaker::acquire`]. Dropping a half-open trial without
+/// recording its outcome, e.g. when the caller's future is cancelled, frees its slot.
+struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    fc: &'a FlowContext,
+    trial: bool,
+}
+
+impl Permit<'_> {
+    fn record(mut self, success: bool) {
+        self.trial = false;
+        self.breaker.record(self.fc, success);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if self.trial {
+            self.breaker.abandon(self.fc);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures::FutureExt;
+
+    use crate::circuit_breaker::{CircuitBreaker, CircuitError, CircuitState};
+    use crate::flow_logger::FlowContext;
+    use crate::monitoring::{ErrorTagger, metrics};
+    use crate::settings::CircuitBreakerSettings;
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    #[derive(Debug)]
+    struct Boom;
+
+    impl ErrorTagger for Boom {
+        fn error_tag(&self) -> String {
+            "boom".to_string()
+        }
+    }
+
+    fn call(breaker: &CircuitBreaker, ok: bool) -> Result<i32, CircuitError<Boom>> {
+        let fc = FlowContext::new("circuit-test");
+        aw!(breaker.call(&fc, || async move { if ok { Ok(1) } else { Err(Boom) } }))
+    }
+
+    #[test]
+    fn opens_fails_fast_and_recovers() {
+        let breaker = CircuitBreaker::new("circuit_test", &CircuitBreakerSettings {
+            failure_threshold: 2,
+            cool_down_ms: 20,
+            half_open_max_calls: 1,
+        });
+
+        assert_eq!(call(&breaker, false).unwrap_err().error_tag(), "boom");
+        assert!(call(&breaker, true).is_ok());
+        assert!(call(&breaker, false).is_err());
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(call(&breaker, false).is_err());
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        let rejected = call(&breaker, true).unwrap_err();
+        assert!(matches!(rejected, CircuitError::Open(_)));
+        assert_eq!(rejected.error_tag(), "circuit-open");
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(call(&breaker, false).is_err());
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(call(&breaker, true).unwrap(), 1);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        let output = metrics();
+        assert!(output.contains("circuit_breaker_state{name=\"circuit_test\",state=\"closed\"} 1"), "{}", output);
+        assert!(output.contains("circuit_breaker_state{name=\"circuit_test\",state=\"open\"} 0"));
+        assert!(output.contains("circuit_breaker_transitions_total{from=\"closed\",name=\"circuit_test\",to=\"open\"} 1"));
+        assert!(output.contains("circuit_breaker_transitions_total{from=\"half-open\",name=\"circuit_test\",to=\"open\"} 1"));
+        assert!(output.contains("circuit_breaker_rejected_total{name=\"circuit_test\"} 1"));
+    }
+
+    #[test]
+    fn dropped_trial_reopens_the_circuit() {
+        let breaker = CircuitBreaker::new("circuit_drop_test", &CircuitBreakerSettings {
+            failure_threshold: 1,
+            cool_down_ms: 20,
+            half_open_max_calls: 1,
+        });
+        assert!(call(&breaker, false).is_err());
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        std::thread::sleep(Duration::from_millis(30));
+        let fc = FlowContext::new("circuit-test");
+        let trial = breaker.call(&fc, futures::future::pending::<Result<i32, Boom>>);
+        assert!(trial.now_or_never().is_none());
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(call(&breaker, true).unwrap(), 1);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+}
@@ -1,23 +1,333 @@
-use deadpool_postgres::{Config, ManagerConfig, Pool, RecyclingMethod};
+use std::fmt;
+use std::fs;
+use std::future::Future;
+use std::str::FromStr;
+use std::time::Duration;
+
+use deadpool_postgres::{Config, ManagerConfig, Pool, PoolConfig, RecyclingMethod, Runtime};
+use deadpool_postgres::config::SslMode;
+use native_tls::{Certificate, Identity, TlsConnector};
+use postgres_native_tls::MakeTlsConnector;
 use tokio_postgres::NoTls;
 use url::Url;
 
-use crate::proper_rust::settings::Database;
+use crate::flow_logger::FlowContext;
+use crate::monitoring;
+use crate::redaction::Redactor;
+use crate::settings::{Database, DatabasePool};
+use crate::telemetry::{Span, SpanKind};
+
+/// The libpq `sslmode` values we support.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TlsMode {
+    Disable,
+    Prefer,
+    Require,
+    VerifyCa,
+    VerifyFull,
+}
+
+impl FromStr for TlsMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disable" => Ok(TlsMode::Disable),
+            "prefer" => Ok(TlsMode::Prefer),
+            "require" => Ok(TlsMode::Require),
+            "verify-ca" => Ok(TlsMode::VerifyCa),
+            "verify-full" => Ok(TlsMode::VerifyFull),
+            other => Err(format!("unsupported sslmode {}", other)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsOptions {
+    pub mode: TlsMode,
+    pub root_cert: Option<String>,
+    pub cert: Option<String>,
+    pub key: Option<String>,
+}
+
+impl TlsOptions {
+    /// Reads `sslmode`, `sslrootcert`, `sslcert` and `sslkey` from the url query, letting
+    /// values set on `Database` take precedence. Defaults to `prefer`, like libpq.
+    pub fn new(database: &Database, url: &Url) -> Result<TlsOptions, String> {
+        let query_param = |name: &str| {
+            url.query_pairs()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.to_string())
+        };
+
+        let mode = match database.sslmode.clone().or_else(|| query_param("sslmode")) {
+            Some(mode) => mode.parse()?,
+            None => TlsMode::Prefer,
+        };
+
+        let cert = database.sslcert.clone().or_else(|| query_param("sslcert"));
+        let key = database.sslkey.clone().or_else(|| query_param("sslkey"));
+        if cert.is_some() != key.is_some() {
+            return Err("sslcert and sslkey must be set together".to_string());
+        }
+
+        Ok(TlsOptions {
+            mode,
+            root_cert: database.sslrootcert.clone().or_else(|| query_param("sslrootcert")),
+            cert,
+            key,
+        })
+    }
+
+    fn ssl_mode(&self) -> SslMode {
+        match self.mode {
+            TlsMode::Disable => SslMode::Disable,
+            TlsMode::Prefer => SslMode::Prefer,
+            TlsMode::Require | TlsMode::VerifyCa | TlsMode::VerifyFull => SslMode::Require,
+        }
+    }
 
-pub fn create_pool(database: &Database) -> Pool {
+    /// Client certificates are read as PEM, with the key in PKCS#8 form.
+    fn connector(&self) -> Result<MakeTlsConnector, String> {
+        let mut builder = TlsConnector::builder();
+
+        if let Some(root_cert) = &self.root_cert {
+            let cert = fs::read(root_cert).map_err(|e| e.to_string())
+                .and_then(|pem| Certificate::from_pem(&pem).map_err(|e| e.to_string()))
+                .map_err(|e| format!("failed to load sslrootcert {}: {}", root_cert, e))?;
+            builder.add_root_certificate(cert);
+        }
+        if let (Some(cert), Some(key)) = (&self.cert, &self.key) {
+            let cert_pem = fs::read(cert).map_err(|e| format!("failed to load sslcert {}: {}", cert, e))?;
+            let key_pem = fs::read(key).map_err(|e| format!("failed to load sslkey {}: {}", key, e))?;
+            let identity = Identity::from_pkcs8(&cert_pem, &key_pem)
+                .map_err(|e| format!("failed to load sslcert {} with sslkey {}: {}", cert, key, e))?;
+            builder.identity(identity);
+        }
+        match self.mode {
+            TlsMode::Prefer | TlsMode::Require => {
+                builder.danger_accept_invalid_certs(true);
+            }
+            TlsMode::VerifyCa => {
+                builder.danger_accept_invalid_hostnames(true);
+            }
+            TlsMode::Disable | TlsMode::VerifyFull => {}
+        }
+
+        let connector = builder.build().map_err(|e| e.to_string())?;
+        Ok(MakeTlsConnector::new(connector))
+    }
+}
+
+#[derive(Debug)]
+pub enum DatabaseError {
+    InvalidUrl(String),
+    InvalidPool(String),
+    Tls(String),
+    Pool(String),
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatabaseError::InvalidUrl(e) => write!(f, "invalid database url: {}", e),
+            DatabaseError::InvalidPool(e) => write!(f, "invalid database pool configuration: {}", e),
+            DatabaseError::Tls(e) => write!(f, "invalid database tls configuration: {}", e),
+            DatabaseError::Pool(e) => write!(f, "failed to create database pool: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DatabaseError {}
+
+pub fn create_pool(database: &Database) -> Result<Pool, DatabaseError> {
     let mut cfg = Config::new();
 
-    let url = Url::parse(database.url.as_str()).unwrap();
-    let url_path: Vec<String> = vec!(url.path_segments().unwrap().collect());
-    let host = url.host_str().unwrap().to_string();
-    let dbname = url_path.first().unwrap().to_string();
+    let shown = Redactor::default().redact(database.url.expose()).into_owned();
+    let url = Url::parse(database.url.expose())
+        .map_err(|e| DatabaseError::InvalidUrl(format!("{}: {}", shown, e)))?;
+    let host = url.host_str()
+        .ok_or_else(|| DatabaseError::InvalidUrl(format!("{}: missing host", shown)))?
+        .to_string();
+    let dbname = url.path_segments()
+        .and_then(|mut segments| segments.next())
+        .filter(|segment| !segment.is_empty())
+        .ok_or_else(|| DatabaseError::InvalidUrl(format!("{}: missing database name", shown)))?
+        .to_string();
+    let tls = TlsOptions::new(database, &url).map_err(DatabaseError::Tls)?;
 
     cfg.dbname = Some(dbname);
     cfg.host = Some(host);
     cfg.port = Some(database.port);
     cfg.user = Some(database.username.to_string());
-    cfg.password = Some(database.password.to_string());
-    cfg.manager = Some(ManagerConfig { recycling_method: RecyclingMethod::Fast });
+    cfg.password = Some(database.password.expose().to_string());
+    cfg.ssl_mode = Some(tls.ssl_mode());
+    configure_pool(&mut cfg, &database.pool)?;
+
+    let pool = match tls.mode {
+        TlsMode::Disable => cfg.create_pool(NoTls),
+        _ => cfg.create_pool(tls.connector().map_err(DatabaseError::Tls)?),
+    };
+    pool.map_err(|e| DatabaseError::Pool(e.to_string()))
+}
+
+pub fn recycling_method(name: &str) -> Result<RecyclingMethod, DatabaseError> {
+    match name {
+        "fast" => Ok(RecyclingMethod::Fast),
+        "verified" => Ok(RecyclingMethod::Verified),
+        "clean" => Ok(RecyclingMethod::Clean),
+        other => Err(DatabaseError::InvalidPool(format!("unsupported recycling_method {}", other))),
+    }
+}
+
+fn configure_pool(cfg: &mut Config, pool: &DatabasePool) -> Result<(), DatabaseError> {
+    let recycling_method = match &pool.recycling_method {
+        Some(name) => recycling_method(name.as_str())?,
+        None => RecyclingMethod::Fast,
+    };
+    cfg.manager = Some(ManagerConfig { recycling_method });
+
+    let mut pool_config = match pool.max_size {
+        Some(max_size) => PoolConfig::new(max_size),
+        None => PoolConfig::default(),
+    };
+    pool_config.timeouts.wait = pool.wait_timeout_ms.map(Duration::from_millis);
+    pool_config.timeouts.create = pool.create_timeout_ms.map(Duration::from_millis);
+    pool_config.timeouts.recycle = pool.recycle_timeout_ms.map(Duration::from_millis);
+    pool_config.runtime = Runtime::Tokio1;
+    cfg.pool = Some(pool_config);
+
+    cfg.connect_timeout = pool.connect_timeout_ms.map(Duration::from_millis);
+    cfg.application_name = pool.application_name.clone();
+    cfg.options = pool.statement_timeout_ms.map(|ms| format!("-c statement_timeout={}", ms));
+    Ok(())
+}
+
+/// Publishes the pool's size, available and waiting counts as gauges on every scrape.
+pub fn register_pool_metrics(pool: &Pool) {
+    let pool = pool.clone();
+    monitoring::on_scrape(move || {
+        let status = pool.status();
+        let gauge = |name: &str, value: f64| {
+            monitoring::gauge_vec(name, &[]).with_label_values(&[]).set(value);
+        };
+        gauge("db_pool_max_size", status.max_size as f64);
+        gauge("db_pool_size", status.size as f64);
+        gauge("db_pool_available", status.available.max(0) as f64);
+        gauge("db_pool_waiting", (-status.available).max(0) as f64);
+    });
+}
+
+/// Runs a database call inside a client span of `fc` tagged with `statement`.
+pub async fn traced<F, T, E>(fc: &FlowContext, statement: &str, f: impl FnOnce() -> F) -> Result<T, E>
+    where
+        F: Future<Output=Result<T, E>>,
+        E: fmt::Display,
+{
+    let operation = statement.split_whitespace().next().unwrap_or("query").to_uppercase();
+    let mut span = Span::start(format!("postgres {}", operation).as_str(), SpanKind::Client, &fc.trace);
+    span.set_attribute("db.system", "postgresql");
+    span.set_attribute("db.statement", statement);
+
+    let res = f().await;
+    span.end(res.as_ref().err().map(|e| e.to_string()));
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use url::Url;
+
+    use crate::database::{create_pool, DatabaseError, register_pool_metrics, TlsMode, TlsOptions};
+    use crate::monitoring::metrics;
+    use crate::settings::Database;
+
+    fn database(url: &str) -> Database {
+        Database { url: url.into(), ..Database::default() }
+    }
+
+    #[test]
+    fn tls_options_from_url() {
+        let db = database("postgresql://localhost/db?sslmode=verify-ca&sslrootcert=/certs/ca.pem");
+        let tls = TlsOptions::new(&db, &Url::parse(db.url.expose()).unwrap()).unwrap();
+
+        assert_eq!(tls, TlsOptions {
+            mode: TlsMode::VerifyCa,
+            root_cert: Some("/certs/ca.pem".to_string()),
+            cert: None,
+            key: None,
+        });
+    }
+
+    #[test]
+    fn tls_options_settings_override_url() {
+        let mut db = database("postgresql://localhost/db?sslmode=verify-ca");
+        db.sslmode = Some("disable".to_string());
+        let tls = TlsOptions::new(&db, &Url::parse(db.url.expose()).unwrap()).unwrap();
+        assert_eq!(tls.mode, TlsMode::Disable);
+
+        let db = database("postgresql://localhost/db");
+        let tls = TlsOptions::new(&db, &Url::parse(db.url.expose()).unwrap()).unwrap();
+        assert_eq!(tls.mode, TlsMode::Prefer);
+
+        let db = database("postgresql://localhost/db?sslmode=allow-anything");
+        assert!(TlsOptions::new(&db, &Url::parse(db.url.expose()).unwrap()).is_err());
+    }
+
+    #[test]
+    fn client_cert_needs_a_key() {
+        let mut db = database("postgresql://localhost/db?sslmode=verify-full&sslcert=/certs/client.pem");
+        match create_pool(&db) {
+            Err(DatabaseError::Tls(e)) => assert_eq!(e, "sslcert and sslkey must be set together"),
+            _ => panic!("expected invalid tls configuration"),
+        }
+
+        db.sslkey = Some("/certs/client.key".to_string());
+        let tls = TlsOptions::new(&db, &Url::parse(db.url.expose()).unwrap()).unwrap();
+        assert_eq!(tls.key, Some("/certs/client.key".to_string()));
+    }
+
+    #[test]
+    fn unreadable_certificates_fail_pool_creation() {
+        let db = database("postgresql://localhost/db?sslmode=verify-ca&sslrootcert=/nonexistent/ca.pem");
+        match create_pool(&db) {
+            Err(DatabaseError::Tls(e)) => assert!(e.starts_with("failed to load sslrootcert /nonexistent/ca.pem: "), "{}", e),
+            _ => panic!("expected invalid tls configuration"),
+        }
+
+        let db = database("postgresql://localhost/db?sslmode=require");
+        assert!(create_pool(&db).is_ok());
+    }
+
+    #[test]
+    fn create_pool_reports_invalid_urls() {
+        match create_pool(&database("not a url")) {
+            Err(DatabaseError::InvalidUrl(_)) => {}
+            _ => panic!("expected invalid url"),
+        }
+        match create_pool(&database("postgresql://localhost/")) {
+            Err(DatabaseError::InvalidUrl(e)) => assert_eq!(e, "postgresql://localhost/: missing database name"),
+            _ => panic!("expected missing database name"),
+        }
+    }
+
+    #[test]
+    fn pool_settings_and_metrics() {
+        let mut db = database("postgresql://localhost/db?sslmode=disable");
+        db.pool.max_size = Some(3);
+        db.pool.recycling_method = Some("verified".to_string());
+        let pool = create_pool(&db).unwrap();
+        assert_eq!(pool.status().max_size, 3);
+
+        register_pool_metrics(&pool);
+        let output = metrics();
+        assert!(output.contains("db_pool_max_size 3"));
+        assert!(output.contains("db_pool_waiting 0"));
 
-    cfg.create_pool(NoTls).unwrap()
+        db.pool.recycling_method = Some("sometimes".to_string());
+        match create_pool(&db) {
+            Err(DatabaseError::InvalidPool(_)) => {}
+            _ => panic!("expected invalid pool configuration"),
+        }
+    }
 }
@@ -0,0 +1,280 @@
+use std::fmt;
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+use warp::{Filter, Rejection, Reply};
+use warp::filters::body::BodyDeserializeError;
+use warp::http::{HeaderValue, StatusCode};
+use warp::http::header::CONTENT_TYPE;
+use warp::reject::{InvalidHeader, InvalidQuery, LengthRequired, MethodNotAllowed, MissingHeader, PayloadTooLarge, Reject, UnsupportedMediaType};
+use warp::reply::Response;
+
+use crate::flow_logger;
+use crate::circuit_breaker::{CIRCUIT_OPEN_TAG, CircuitError};
+use crate::flow_logger::{FlowContext, FlowLogger};
+use crate::http_metrics::RoutedRejection;
+use crate::monitoring::ErrorTagger;
+
+lazy_static! {
+    static ref LOG: FlowLogger = flow_logger!("proper_rust::errors");
+}
+
+/// Application errors raised as warp rejections and rendered by [`handle_rejections`].
+#[derive(Debug)]
+pub enum AppError {
+    Upstream(String),
+    Validation(Vec<String>),
+    NotFound(String),
+    Conflict(String),
+    Database(String),
+    /// A circuit breaker is open; the dependency was not called.
+    CircuitOpen(String),
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            AppError::Validation(_) => StatusCode::BAD_REQUEST,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::Database(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::CircuitOpen(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    /// Message safe to return to callers; upstream and database details are only logged.
+    fn detail(&self) -> Option<String> {
+        match self {
+            AppError::Upstream(_) => Some("upstream service call failed".to_string()),
+            AppError::Validation(_) => Some("invalid request".to_string()),
+            AppError::NotFound(what) => Some(format!("{} not found", what)),
+            AppError::Conflict(what) => Some(format!("{} already exists", what)),
+            AppError::Database(_) => Some("database unavailable".to_string()),
+            AppError::CircuitOpen(_) => Some("upstream service temporarily unavailable".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Upstream(e) => write!(f, "upstream failure: {}", e),
+            AppError::Validation(problems) => write!(f, "validation failed: {}", problems.join(", ")),
+            AppError::NotFound(what) => write!(f, "{} not found", what),
+            AppError::Conflict(what) => write!(f, "{} already exists", what),
+            AppError::Database(e) => write!(f, "database failure: {}", e),
+            AppError::CircuitOpen(name) => write!(f, "circuit {} is open", name),
+        }
+    }
+}
+
+impl Reject for AppError {}
+
+impl<E: fmt::Display> From<CircuitError<E>> for AppError {
+    fn from(e: CircuitError<E>) -> Self {
+        match e {
+            CircuitError::Open(name) => AppError::CircuitOpen(name),
+            CircuitError::Inner(e) => AppError::Upstream(e.to_string()),
+        }
+    }
+}
+
+impl ErrorTagger for AppError {
+    fn error_tag(&self) -> String {
+        match self {
+            AppError::Upstream(_) => "upstream",
+            AppError::Validation(_) => "validation",
+            AppError::NotFound(_) => "not-found",
+            AppError::Conflict(_) => "conflict",
+            AppError::Database(_) => "database",
+            AppError::CircuitOpen(_) => CIRCUIT_OPEN_TAG,
+        }.to_string()
+    }
+}
+
+impl ErrorTagger for Rejection {
+    fn error_tag(&self) -> String {
+        let rejection = cause(self);
+        match rejection.find::<AppError>() {
+            Some(e) => e.error_tag(),
+            None if rejection.is_not_found() => "not-found".to_string(),
+            None => "rejection".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Problem<'a> {
+    status: u16,
+    title: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<String>,
+    #[serde(rename = "flow-id")]
+    flow_id: &'a str,
+}
+
+/// The rejection a handler raised, looking through the tag added by [`route`](crate::http_metrics::route).
+fn cause(rejection: &Rejection) -> &Rejection {
+    rejection.find::<RoutedRejection>().map(|r| &r.rejection).unwrap_or(rejection)
+}
+
+/// Status warp itself would answer with for a rejection it produced.
+pub fn rejection_status(rejection: &Rejection) -> StatusCode {
+    let rejection = cause(rejection);
+    if let Some(e) = rejection.find::<AppError>() {
+        e.status()
+    } else if rejection.is_not_found() {
+        StatusCode::NOT_FOUND
+    } else if rejection.find::<MethodNotAllowed>().is_some() {
+        StatusCode::METHOD_NOT_ALLOWED
+    } else if rejection.find::<InvalidHeader>().is_some()
+        || rejection.find::<MissingHeader>().is_some()
+        || rejection.find::<InvalidQuery>().is_some()
+        || rejection.find::<BodyDeserializeError>().is_some() {
+        StatusCode::BAD_REQUEST
+    } else if rejection.find::<LengthRequired>().is_some() {
+        StatusCode::LENGTH_REQUIRED
+    } else if rejection.find::<PayloadTooLarge>().is_some() {
+        StatusCode::PAYLOAD_TOO_LARGE
+    } else if rejection.find::<UnsupportedMediaType>().is_some() {
+        StatusCode::UNSUPPORTED_MEDIA_TYPE
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+/// Renders any rejection as an `application/problem+json` body carrying the flow-id, and
+/// the route template when the rejection was raised by a tagged route.
+pub fn problem_response(fc: &FlowContext, rejection: &Rejection) -> Response {
+    let routed = rejection.find::<RoutedRejection>();
+    let rejection = cause(rejection);
+    let status = rejection_status(rejection);
+    let app_error = rejection.find::<AppError>();
+
+    let (detail, errors) = match app_error {
+        Some(AppError::Validation(problems)) => (app_error.and_then(AppError::detail), problems.clone()),
+        Some(e) => (e.detail(), Vec::new()),
+        None => (None, Vec::new()),
+    };
+
+    if status.is_server_error() {
+        let cause = app_error.map(|e| e.to_string()).unwrap_or_else(|| format!("{:?}", rejection));
+        LOG.error(fc, format!("request failed with {}: {}", status.as_u16(), cause).as_str());
+    }
+
+    let problem = Problem {
+        status: status.as_u16(),
+        title: status.canonical_reason().unwrap_or("Error"),
+        detail,
+        errors,
+        flow_id: fc.flow_id.as_str(),
+    };
+    let mut response = warp::reply::with_status(warp::reply::json(&problem), status).into_response();
+    response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/problem+json"));
+    if let Some(routed) = routed {
+        response.extensions_mut().insert(routed.route);
+    }
+    response
+}
+
+/// Turns every rejection from `filter` into a problem response.
+pub fn handle_rejections<F, R>(filter: F) -> impl Filter<Extract=(Response, ), Error=Rejection> + Clone
+    where
+        F: Filter<Extract=(R, ), Error=Rejection> + Clone + Send + Sync + 'static,
+        R: Reply + 'static,
+{
+    let outcome = filter
+        .map(|reply: R| Ok(reply.into_response()))
+        .or_else(|rejection| async move { Ok::<_, Rejection>((Err(rejection), )) });
+
+    FlowContext::extract_flow_context()
+        .and(outcome)
+        .map(|fc: FlowContext, outcome: Result<Response, Rejection>| match outcome {
+            Ok(response) => response,
+            Err(rejection) => problem_response(&fc, &rejection),
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use warp::Filter;
+    use warp::http::StatusCode;
+
+    use crate::errors::{AppError, handle_rejections};
+    use crate::monitoring::ErrorTagger;
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    #[test]
+    fn renders_problem_bodies_with_flow_id() {
+        let filter = handle_rejections(
+            warp::path!("items" / String).and_then(|name: String| async move {
+                match name.as_str() {
+                    "missing" => Err(warp::Rejection::from(AppError::NotFound(format!("item {}", name)))),
+                    "bad" => Err(AppError::Validation(vec!["name is bad".to_string()]).into()),
+                    _ => Err(AppError::Upstream("connection refused".to_string()).into()),
+                }.map(|()| warp::reply())
+            })
+        );
+
+        let res = aw!(warp::test::request().path("/items/missing").header("flow-id", "f-1").reply(&filter));
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(res.headers()["content-type"], "application/problem+json");
+        assert_eq!(
+            String::from_utf8_lossy(res.body()),
+            "{\"status\":404,\"title\":\"Not Found\",\"detail\":\"item missing not found\",\"flow-id\":\"f-1\"}"
+        );
+
+        let res = aw!(warp::test::request().path("/items/bad").header("flow-id", "f-2").reply(&filter));
+        assert_eq!(
+            String::from_utf8_lossy(res.body()),
+            "{\"status\":400,\"title\":\"Bad Request\",\"detail\":\"invalid request\",\"errors\":[\"name is bad\"],\"flow-id\":\"f-2\"}"
+        );
+
+        let res = aw!(warp::test::request().path("/items/other").reply(&filter));
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+
+        let res = aw!(warp::test::request().path("/nothing/here").header("flow-id", "f-3").reply(&filter));
+        assert_eq!(
+            String::from_utf8_lossy(res.body()),
+            "{\"status\":404,\"title\":\"Not Found\",\"flow-id\":\"f-3\"}"
+        );
+    }
+
+    #[derive(Debug)]
+    struct Internal;
+
+    impl warp::reject::Reject for Internal {}
+
+    #[test]
+    fn never_exposes_rejection_internals() {
+        let filter = handle_rejections(warp::any().and_then(|| async {
+            Err::<String, _>(warp::reject::custom(Internal))
+        }));
+
+        let res = aw!(warp::test::request().header("flow-id", "f-4").reply(&filter));
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            String::from_utf8_lossy(res.body()),
+            "{\"status\":500,\"title\":\"Internal Server Error\",\"flow-id\":\"f-4\"}"
+        );
+    }
+
+    #[test]
+    fn tags_rejections_by_kind() {
+        let tag = |e: AppError| warp::Rejection::from(e).error_tag();
+        assert_eq!(tag(AppError::Upstream("".to_string())), "upstream");
+        assert_eq!(tag(AppError::Validation(vec![])), "validation");
+        assert_eq!(tag(AppError::NotFound("".to_string())), "not-found");
+        assert_eq!(tag(AppError::Conflict("".to_string())), "conflict");
+        assert_eq!(tag(AppError::Database("".to_string())), "database");
+        assert_eq!(warp::reject::not_found().error_tag(), "not-found");
+    }
+}
@@ -0,0 +1,299 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use deadpool_postgres::Pool;
+use futures::future::join_all;
+use reqwest::Method;
+use serde::Serialize;
+use tokio::time::timeout;
+use warp::Filter;
+use warp::http::StatusCode;
+
+use crate::http_client::HttpClient;
+use crate::shutdown::Shutdown;
+
+#[async_trait]
+pub trait HealthCheck: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Whether a failure of this check makes the service not ready.
+    fn critical(&self) -> bool {
+        true
+    }
+
+    async fn check(&self) -> Result<(), String>;
+}
+
+pub struct PostgresHealthCheck {
+    pool: Pool,
+}
+
+impl PostgresHealthCheck {
+    pub fn new(pool: Pool) -> Self {
+        PostgresHealthCheck { pool }
+    }
+}
+
+#[async_trait]
+impl HealthCheck for PostgresHealthCheck {
+    fn name(&self) -> &str {
+        "postgres"
+    }
+
+    async fn check(&self) -> Result<(), String> {
+        let client = self.pool.get().await.map_err(|e| e.to_string())?;
+        let stmt = client.prepare_cached("SELECT 1").await.map_err(|e| e.to_string())?;
+        client.query_one(&stmt, &[]).await.map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+/// Probes `url` with a HEAD request, so checks never download or trigger work on a body.
+/// Requests go through the shared client's pool and timeouts, but are never retried.
+pub struct HttpHealthCheck {
+    name: String,
+    url: String,
+    critical: bool,
+    client: HttpClient,
+}
+
+impl HttpHealthCheck {
+    pub fn new(name: &str, url: &str, critical: bool, client: HttpClient) -> Self {
+        HttpHealthCheck {
+            name: name.to_string(),
+            url: url.to_string(),
+            critical,
+            client,
+        }
+    }
+}
+
+#[async_trait]
+impl HealthCheck for HttpHealthCheck {
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn critical(&self) -> bool {
+        self.critical
+    }
+
+    async fn check(&self) -> Result<(), String> {
+        let res = self.client.request(Method::HEAD, self.url.as_str()).send().await.map_err(|e| e.to_string())?;
+        if res.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("unexpected status {}", res.status()))
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CheckReport {
+    name: String,
+    status: &'static str,
+    critical: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct HealthReport {
+    status: &'static str,
+    checks: Vec<CheckReport>,
+}
+
+#[derive(Clone)]
+pub struct Health {
+    checks: Vec<Arc<dyn HealthCheck>>,
+    check_timeout: Duration,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Health {
+            checks: Vec::new(),
+            check_timeout: Duration::from_secs(5),
+        }
+    }
+
+    pub fn with_check(mut self, check: impl HealthCheck + 'static) -> Self {
+        self.checks.push(Arc::new(check));
+        self
+    }
+
+    /// How long each check may take before it is reported as `timed out`; defaults to 5s.
+    pub fn with_check_timeout(mut self, check_timeout: Duration) -> Self {
+        self.check_timeout = check_timeout;
+        self
+    }
+
+    async fn report(&self) -> HealthReport {
+        let results = join_all(self.checks.iter().map(|check| async move {
+            let error = match timeout(self.check_timeout, check.check()).await {
+                Ok(Ok(())) => None,
+                Ok(Err(e)) => Some(e),
+                Err(_) => Some("timed out".to_string()),
+            };
+            CheckReport {
+                name: check.name().to_string(),
+                status: if error.is_none() { "UP" } else { "DOWN" },
+                critical: check.critical(),
+                error,
+            }
+        })).await;
+
+        let ready = results.iter().all(|r| !r.critical || r.error.is_none());
+        HealthReport {
+            status: if ready { "UP" } else { "DOWN" },
+            checks: results,
+        }
+    }
+
+    /// `GET /health/live` and `GET /health/ready`. Readiness fails once shutdown has started.
+    pub fn routes(self, shutdown: Shutdown) -> impl Filter<Extract=(impl warp::Reply, ), Error=warp::Rejection> + Clone {
+        let live = warp::get()
+            .and(warp::path!("health" / "live"))
+            .map(|| warp::reply::json(&HealthReport { status: "UP", checks: Vec::new() }));
+
+        let ready = warp::get()
+            .and(warp::path!("health" / "ready"))
+            .and(warp::any().map(move || self.clone()))
+            .and(warp::any().map(move || shutdown.clone()))
+            .and_then(ready);
+
+        live.or(ready)
+    }
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health::new()
+    }
+}
+
+async fn ready(health: Health, shutdown: Shutdown) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut report = health.report().await;
+    if shutdown.is_triggered() {
+        report.status = "DOWN";
+    }
+    let status = if report.status == "UP" { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    Ok(warp::reply::with_status(warp::reply::json(&report), status))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use mockito::mock;
+    use warp::http::StatusCode;
+
+    use crate::health::{Health, HealthCheck, HttpHealthCheck};
+    use crate::http_client::HttpClient;
+    use crate::settings::HttpClientSettings;
+    use crate::shutdown::Shutdown;
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    struct StaticCheck {
+        name: &'static str,
+        critical: bool,
+        healthy: bool,
+    }
+
+    #[async_trait]
+    impl HealthCheck for StaticCheck {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn critical(&self) -> bool {
+            self.critical
+        }
+
+        async fn check(&self) -> Result<(), String> {
+            if self.healthy { Ok(()) } else { Err("broken".to_string()) }
+        }
+    }
+
+    #[test]
+    fn ready_ignores_non_critical_failures() {
+        let routes = Health::new()
+            .with_check(StaticCheck { name: "db", critical: true, healthy: true })
+            .with_check(StaticCheck { name: "upstream", critical: false, healthy: false })
+            .routes(Shutdown::new());
+
+        let res = aw!(warp::test::request().path("/health/ready").reply(&routes));
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            String::from_utf8_lossy(res.body()),
+            "{\"status\":\"UP\",\"checks\":[\
+             {\"name\":\"db\",\"status\":\"UP\",\"critical\":true},\
+             {\"name\":\"upstream\",\"status\":\"DOWN\",\"critical\":false,\"error\":\"broken\"}]}"
+        );
+    }
+
+    #[test]
+    fn ready_fails_on_critical_failure_or_shutdown() {
+        let failing = Health::new()
+            .with_check(StaticCheck { name: "db", critical: true, healthy: false })
+            .routes(Shutdown::new());
+        let res = aw!(warp::test::request().path("/health/ready").reply(&failing));
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let shutdown = Shutdown::new();
+        shutdown.trigger();
+        let draining = Health::new().routes(shutdown);
+        let res = aw!(warp::test::request().path("/health/ready").reply(&draining));
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let res = aw!(warp::test::request().path("/health/live").reply(&draining));
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    struct SlowCheck;
+
+    #[async_trait]
+    impl HealthCheck for SlowCheck {
+        fn name(&self) -> &str {
+            "slow"
+        }
+
+        async fn check(&self) -> Result<(), String> {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn checks_time_out() {
+        let routes = Health::new()
+            .with_check(SlowCheck)
+            .with_check_timeout(Duration::from_millis(10))
+            .routes(Shutdown::new());
+
+        let res = aw!(warp::test::request().path("/health/ready").reply(&routes));
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            String::from_utf8_lossy(res.body()),
+            "{\"status\":\"DOWN\",\"checks\":[{\"name\":\"slow\",\"status\":\"DOWN\",\"critical\":true,\"error\":\"timed out\"}]}"
+        );
+    }
+
+    #[test]
+    fn http_check_probes_with_head() {
+        let head = mock("HEAD", "/health-probe").with_status(200).expect(2).create();
+        let client = HttpClient::new(&HttpClientSettings::default()).unwrap();
+        let check = HttpHealthCheck::new("probe", &[mockito::SERVER_URL, "/health-probe"].join(""), true, client);
+
+        assert_eq!(aw!(check.check()), Ok(()));
+        assert_eq!(aw!(check.check()), Ok(()));
+        head.assert();
+    }
+}
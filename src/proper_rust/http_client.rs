@@ -0,0 +1,261 @@
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use rand::Rng;
+use reqwest::{Method, Request, RequestBuilder, Response, StatusCode};
+use serde_json::{json, Value};
+
+use crate::flow_logger;
+use crate::flow_logger::{FlowContext, FlowLogger, PropagateFlowContext};
+use crate::monitoring;
+use crate::settings::HttpClientSettings;
+use crate::telemetry::{Span, SpanKind};
+
+const CLIENT_LABELS: [&str; 3] = ["host", "method", "status"];
+
+lazy_static! {
+    static ref LOG: FlowLogger = flow_logger!("proper_rust::http_client");
+}
+
+#[derive(Clone, Copy, Debug)]
+struct RetryPolicy {
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Full jitter: a random delay up to `initial_backoff * 2^attempt`, capped at `max_backoff`.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let cap = self.initial_backoff
+            .checked_mul(2u32.saturating_pow(attempt))
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff);
+        let millis = cap.as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(0..=millis))
+    }
+}
+
+/// Pooled client for calls to other services. Build it once and clone it; clones share
+/// the connection pool. Requests carry the flow context and get a client span.
+#[derive(Clone, Debug)]
+pub struct HttpClient {
+    client: reqwest::Client,
+    retry: RetryPolicy,
+}
+
+impl HttpClient {
+    pub fn new(settings: &HttpClientSettings) -> Result<HttpClient, reqwest::Error> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_millis(settings.connect_timeout_ms))
+            .timeout(Duration::from_millis(settings.request_timeout_ms))
+            .pool_idle_timeout(Duration::from_millis(settings.pool_idle_timeout_ms))
+            .pool_max_idle_per_host(settings.pool_max_idle_per_host)
+            .build()?;
+        Ok(HttpClient {
+            client,
+            retry: RetryPolicy {
+                max_retries: settings.max_retries,
+                initial_backoff: Duration::from_millis(settings.initial_backoff_ms),
+                max_backoff: Duration::from_millis(settings.max_backoff_ms),
+            },
+        })
+    }
+
+    pub fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        self.client.request(method, url)
+    }
+
+    pub fn get(&self, url: &str) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    /// Sends `request` as part of `fc`. Idempotent requests are retried with backoff after
+    /// connection failures, timeouts and 429/502/503/504 responses.
+    pub async fn send(&self, fc: &FlowContext, request: RequestBuilder) -> Result<Response, reqwest::Error> {
+        let mut request = request.build()?;
+        let mut attempt = 0;
+        loop {
+            // Requests with a streaming body cannot be cloned, so they are never retried.
+            let retry = if idempotent(request.method()) && attempt < self.retry.max_retries {
+                request.try_clone()
+            } else {
+                None
+            };
+            let result = self.execute(fc, request).await;
+            let next = match retry {
+                Some(next) => next,
+                None => return result,
+            };
+            let reason = match &result {
+                Ok(res) if retryable_status(res.status()) => res.status().to_string(),
+                Err(e) if e.is_connect() || e.is_timeout() => e.to_string(),
+                _ => return result,
+            };
+
+            let delay = self.retry.backoff(attempt);
+            attempt += 1;
+            LOG.warn_kv(fc, "retrying upstream request", &[
+                ("method", Value::from(next.method().as_str())),
+                ("host", Value::from(next.url().host_str().unwrap_or(""))),
+                ("attempt", json!(attempt)),
+                ("delay_ms", json!(delay.as_millis() as u64)),
+                ("reason", Value::from(reason)),
+            ]);
+            tokio::time::sleep(delay).await;
+            request = next;
+        }
+    }
+
+    async fn execute(&self, fc: &FlowContext, request: Request) -> Result<Response, reqwest::Error> {
+        let method = request.method().to_string();
+        let host = request.url().host_str().unwrap_or("").to_string();
+        let mut span = Span::start(format!("HTTP {}", method).as_str(), SpanKind::Client, &fc.trace);
+        span.set_attribute("http.method", method.as_str());
+        span.set_attribute("net.peer.name", host.as_str());
+        let request = request.flow_context(&fc.with_trace(span.context().clone()));
+
+        LOG.debug_kv(fc, "upstream request", &[
+            ("method", method.as_str()),
+            ("host", host.as_str()),
+            ("path", request.url().path()),
+        ]);
+        let start = Instant::now();
+        let result = self.client.execute(request).await;
+        let elapsed = start.elapsed();
+
+        let status_class = match &result {
+            Ok(res) => format!("{}xx", res.status().as_u16() / 100),
+            Err(_) => "error".to_string(),
+        };
+        let labels = [host.as_str(), method.as_str(), status_class.as_str()];
+        monitoring::counter_vec("http_client_requests_total", &CLIENT_LABELS)
+            .with_label_values(&labels)
+            .inc();
+        monitoring::histogram_vec("http_client_request_duration_seconds", &CLIENT_LABELS)
+            .with_label_values(&labels)
+            .observe(elapsed.as_secs_f64());
+
+        let mut fields = vec![
+            ("method", Value::from(method.as_str())),
+            ("host", Value::from(host.as_str())),
+            ("duration_ms", json!(elapsed.as_millis() as u64)),
+        ];
+        match &result {
+            Ok(res) => {
+                fields.push(("status", json!(res.status().as_u16())));
+                LOG.debug_kv(fc, "upstream response", &fields);
+                span.set_attribute("http.status_code", res.status().as_u16());
+                let error = if res.status().is_server_error() { Some(res.status().to_string()) } else { None };
+                span.end(error);
+            }
+            Err(e) => {
+                fields.push(("error", Value::from(e.to_string())));
+                LOG.debug_kv(fc, "upstream request failed", &fields);
+                span.end(Some(e.to_string()));
+            }
+        }
+        result
+    }
+}
+
+fn idempotent(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE)
+}
+
+fn retryable_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use mockito::mock;
+
+    use crate::flow_logger::FlowContext;
+    use crate::http_client::{HttpClient, RetryPolicy};
+    use crate::monitoring::metrics;
+    use crate::settings::HttpClientSettings;
+
+    fn client() -> HttpClient {
+        HttpClient::new(&HttpClientSettings {
+            max_retries: 2,
+            initial_backoff_ms: 1,
+            max_backoff_ms: 5,
+            ..HttpClientSettings::default()
+        }).unwrap()
+    }
+
+    #[test]
+    fn retries_idempotent_requests_only() {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let client = client();
+        let fc = FlowContext::new("retry-flow");
+
+        let get = mock("GET", "/flaky")
+            .match_header("flow-id", "retry-flow")
+            .with_status(503)
+            .expect(3)
+            .create();
+        let res = rt.block_on(client.send(&fc, client.get(&[mockito::SERVER_URL, "/flaky"].join("")))).unwrap();
+        assert_eq!(res.status(), 503);
+        get.assert();
+
+        let put = mock("PUT", "/flaky-put")
+            .match_body("{\"quantity\":2}")
+            .with_status(502)
+            .expect(3)
+            .create();
+        let url = [mockito::SERVER_URL, "/flaky-put"].join("");
+        let request = client.request(reqwest::Method::PUT, url.as_str()).body("{\"quantity\":2}");
+        let res = rt.block_on(client.send(&fc, request)).unwrap();
+        assert_eq!(res.status(), 502);
+        put.assert();
+
+        let post = mock("POST", "/flaky-post")
+            .with_status(503)
+            .expect(1)
+            .create();
+        let url = [mockito::SERVER_URL, "/flaky-post"].join("");
+        let res = rt.block_on(client.send(&fc, client.request(reqwest::Method::POST, url.as_str()))).unwrap();
+        assert_eq!(res.status(), 503);
+        post.assert();
+
+        let ok = mock("GET", "/ok")
+            .with_status(200)
+            .expect(1)
+            .create();
+        let res = rt.block_on(client.send(&fc, client.get(&[mockito::SERVER_URL, "/ok"].join("")))).unwrap();
+        assert_eq!(res.status(), 200);
+        ok.assert();
+
+        let output = metrics();
+        assert!(output.contains(
+            "http_client_requests_total{host=\"127.0.0.1\",method=\"GET\",status=\"5xx\"} 3"
+        ), "{}", output);
+        assert!(output.contains(
+            "http_client_request_duration_seconds_count{host=\"127.0.0.1\",method=\"POST\",status=\"5xx\"} 1"
+        ));
+        assert!(output.contains(
+            "http_client_requests_total{host=\"127.0.0.1\",method=\"PUT\",status=\"5xx\"} 3"
+        ), "{}", output);
+        assert!(output.contains("http_client_requests_total{host=\"127.0.0.1\",method=\"GET\",status=\"2xx\"}"));
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let retry = RetryPolicy {
+            max_retries: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(1000),
+        };
+
+        assert!(retry.backoff(0) <= Duration::from_millis(100));
+        assert!(retry.backoff(3) <= Duration::from_millis(800));
+        assert!((0..10).all(|_| retry.backoff(40) <= Duration::from_millis(1000)));
+    }
+}
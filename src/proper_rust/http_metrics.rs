@@ -0,0 +1,199 @@
+use std::convert::Infallible;
+use std::time::Instant;
+
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use warp::{Filter, Rejection, Reply};
+use warp::http::{Method, StatusCode};
+use warp::hyper::body::HttpBody;
+use warp::path::FullPath;
+use warp::reject::{MethodNotAllowed, Reject};
+use warp::reply::Response;
+
+use crate::flow_logger;
+use crate::errors::rejection_status;
+use crate::flow_logger::{FlowContext, FlowLogger};
+use crate::monitoring;
+
+const REQUEST_LABELS: [&str; 3] = ["route", "method", "status"];
+const UNKNOWN_ROUTE: &str = "unknown";
+
+lazy_static! {
+    static ref ACCESS_LOG: FlowLogger = flow_logger!("proper_rust::access");
+}
+
+/// Route template attached to a response by [`route`], used as the `route` label.
+#[derive(Clone, Copy, Debug)]
+pub struct RouteTemplate(pub &'static str);
+
+/// A rejection raised after a route matched, tagged with that route by [`route`].
+#[derive(Debug)]
+pub struct RoutedRejection {
+    pub route: RouteTemplate,
+    pub rejection: Rejection,
+}
+
+impl Reject for RoutedRejection {}
+
+/// Tags the replies and rejections of `filter` with the route template they were served by, e.g.
+/// `route("/v1/groceries/{name}", warp::get().and(item).and_then(get_item))`. Rejections saying
+/// the route did not match (not found, method not allowed) are passed on untagged.
+///
+/// warp cannot tell which path pattern matched, so templates are not derived from the filter:
+/// every route has to be wrapped by hand. Requests served by an untagged route, and requests no
+/// route matched, are recorded with `route="unknown"`.
+pub fn route<F, R>(template: &'static str, filter: F) -> impl Filter<Extract=(Response, ), Error=Rejection> + Clone
+    where
+        F: Filter<Extract=(R, ), Error=Rejection> + Clone + Send + Sync + 'static,
+        R: Reply + 'static,
+{
+    filter
+        .map(move |reply: R| {
+            let mut response = reply.into_response();
+            response.extensions_mut().insert(RouteTemplate(template));
+            response
+        })
+        .or_else(move |rejection: Rejection| async move {
+            if rejection.is_not_found() || rejection.find::<MethodNotAllowed>().is_some() {
+                return Err(rejection);
+            }
+            Err::<(Response, ), _>(warp::reject::custom(RoutedRejection { route: RouteTemplate(template), rejection }))
+        })
+}
+
+/// Registers the byte-sized buckets for the response size histogram.
+pub fn init_buckets() {
+    monitoring::set_buckets(
+        "http_response_size_bytes",
+        vec![64.0, 256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0],
+    );
+}
+
+struct RequestInfo {
+    start: Instant,
+    method: Method,
+    path: FullPath,
+    fc: FlowContext,
+}
+
+fn request_info() -> impl Filter<Extract=(RequestInfo, ), Error=Infallible> + Copy {
+    warp::any()
+        .map(Instant::now)
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(FlowContext::extract_flow_context())
+        .map(|start, method, path, fc| RequestInfo { start, method, path, fc })
+}
+
+/// Wraps `filter` so that every request records count, latency and response size metrics
+/// and writes one access log line.
+pub fn instrument<F, R>(filter: F) -> impl Filter<Extract=(Response, ), Error=Rejection> + Clone
+    where
+        F: Filter<Extract=(R, ), Error=Rejection> + Clone + Send + Sync + 'static,
+        R: Reply + 'static,
+{
+    let outcome = filter
+        .map(|reply: R| Ok(reply.into_response()))
+        .or_else(|rejection| async move { Ok::<_, Rejection>((Err(rejection), )) });
+
+    request_info()
+        .and(outcome)
+        .and_then(|info: RequestInfo, outcome: Result<Response, Rejection>| async move {
+            match outcome {
+                Ok(response) => {
+                    let route = response.extensions().get::<RouteTemplate>()
+                        .map(|t| t.0)
+                        .unwrap_or(UNKNOWN_ROUTE);
+                    let size = response.body().size_hint().exact().unwrap_or(0);
+                    record(&info, route, response.status(), size);
+                    Ok(response)
+                }
+                Err(rejection) => {
+                    let route = rejection.find::<RoutedRejection>()
+                        .map(|r| r.route.0)
+                        .unwrap_or(UNKNOWN_ROUTE);
+                    record(&info, route, rejection_status(&rejection), 0);
+                    Err(rejection)
+                }
+            }
+        })
+}
+
+fn record(info: &RequestInfo, route: &str, status: StatusCode, size: u64) {
+    let elapsed = info.start.elapsed();
+    let labels = [route, info.method.as_str(), status.as_str()];
+
+    monitoring::counter_vec("http_requests_total", &REQUEST_LABELS)
+        .with_label_values(&labels)
+        .inc();
+    monitoring::histogram_vec("http_request_duration_seconds", &REQUEST_LABELS)
+        .with_label_values(&labels)
+        .observe(elapsed.as_secs_f64());
+    monitoring::histogram_vec("http_response_size_bytes", &REQUEST_LABELS)
+        .with_label_values(&labels)
+        .observe(size as f64);
+
+    ACCESS_LOG.info_kv(&info.fc, "request completed", &[
+        ("method", Value::from(info.method.as_str())),
+        ("path", Value::from(info.path.as_str())),
+        ("route", Value::from(route)),
+        ("status", json!(status.as_u16())),
+        ("duration_ms", json!(elapsed.as_millis() as u64)),
+        ("bytes", json!(size)),
+    ]);
+}
+
+#[cfg(test)]
+mod test {
+    use warp::Filter;
+    use warp::http::StatusCode;
+
+    use crate::errors::{AppError, handle_rejections};
+    use crate::http_metrics::{instrument, route};
+    use crate::monitoring::metrics;
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    #[test]
+    fn records_route_method_and_status() {
+        let filter = instrument(route(
+            "/http_metrics_test/{name}",
+            warp::path!("http_metrics_test" / String).map(|name: String| name),
+        ));
+
+        let res = aw!(warp::test::request().path("/http_metrics_test/abc").reply(&filter));
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = aw!(warp::test::request().method("POST").path("/http_metrics_missing").reply(&filter));
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let output = metrics();
+        assert!(output.contains("http_requests_total{method=\"GET\",route=\"/http_metrics_test/{name}\",status=\"200\"} 1"));
+        assert!(output.contains("http_response_size_bytes_sum{method=\"GET\",route=\"/http_metrics_test/{name}\",status=\"200\"} 3"));
+        assert!(output.contains("http_requests_total{method=\"POST\",route=\"unknown\",status=\"404\"} 1"));
+    }
+
+    #[test]
+    fn error_responses_keep_their_route() {
+        let filter = instrument(handle_rejections(route(
+            "/v1/groceries/{name}",
+            warp::get()
+                .and(warp::path!("v1" / "groceries" / String))
+                .and_then(|name: String| async move {
+                    Err::<String, _>(warp::Rejection::from(AppError::NotFound(name)))
+                }),
+        )));
+
+        let res = aw!(warp::test::request().path("/v1/groceries/milk").reply(&filter));
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = aw!(warp::test::request().method("DELETE").path("/v1/groceries/milk").reply(&filter));
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        let output = metrics();
+        assert!(output.contains("http_requests_total{method=\"GET\",route=\"/v1/groceries/{name}\",status=\"404\"} 1"), "{}", output);
+        assert!(output.contains("http_requests_total{method=\"DELETE\",route=\"unknown\",status=\"405\"} 1"));
+    }
+}
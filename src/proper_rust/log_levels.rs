@@ -0,0 +1,505 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use log::LevelFilter;
+use log4rs::config::{Config, Deserializers, Logger, RawConfig};
+use log4rs::Handle;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use warp::{Filter, Reply};
+use warp::http::StatusCode;
+use warp::reply::Response;
+
+use crate::flow_logger;
+use crate::errors::{AppError, problem_response};
+use crate::flow_logger::{FlowContext, FlowLogger};
+
+/// Name accepted by the admin endpoint for the root logger.
+pub const ROOT: &str = "root";
+
+/// Longest override the admin endpoint accepts, and the expiry of overrides set without one.
+pub const MAX_EXPIRES_IN_SECONDS: u64 = 24 * 60 * 60;
+
+/// Most loggers the admin endpoint lets be overridden at once.
+pub const MAX_OVERRIDES: usize = 64;
+
+lazy_static! {
+    static ref LOG: FlowLogger = flow_logger!("proper_rust::log_levels");
+    static ref LEVELS: LogLevels = LogLevels::new(RawConfig::default(), Deserializers::default());
+}
+
+#[derive(Clone, Copy)]
+struct Override {
+    level: LevelFilter,
+    expires_at: Option<DateTime<Utc>>,
+    id: u64,
+}
+
+struct State {
+    raw: RawConfig,
+    deserializers: Deserializers,
+    handle: Option<Handle>,
+    overrides: BTreeMap<String, Override>,
+    next_id: u64,
+}
+
+impl State {
+    /// The file's configuration with the overrides applied.
+    fn config(&self) -> Config {
+        let (appenders, mut errors) = self.raw.appenders_lossy(&self.deserializers);
+        errors.handle();
+
+        let mut loggers: Vec<Logger> = self.raw.loggers().into_iter()
+            .map(|logger| match self.overrides.get(logger.name()) {
+                Some(o) => Logger::builder()
+                    .appenders(logger.appenders().to_vec())
+                    .additive(logger.additive())
+                    .build(logger.name(), o.level),
+                None => logger,
+            })
+            .collect();
+        let configured: Vec<String> = loggers.iter().map(|l| l.name().to_string()).collect();
+        loggers.extend(self.overrides.iter()
+            .filter(|(name, _)| name.as_str() != ROOT && !configured.contains(name))
+            .map(|(name, o)| Logger::builder().build(name.as_str(), o.level)));
+
+        let mut root = self.raw.root();
+        if let Some(o) = self.overrides.get(ROOT) {
+            root.set_level(o.level);
+        }
+
+        let (config, mut errors) = Config::builder()
+            .appenders(appenders)
+            .loggers(loggers)
+            .build_lossy(root);
+        errors.handle();
+        config
+    }
+
+    fn apply(&self) {
+        if let Some(handle) = &self.handle {
+            handle.set_config(self.config());
+        }
+    }
+
+    /// Level `name` logs at, inherited from the nearest configured ancestor.
+    fn effective(&self, name: &str) -> LevelFilter {
+        if let Some(o) = self.overrides.get(name) {
+            return o.level;
+        }
+        self.levels().into_iter()
+            .filter(|(logger, _)| name == logger
+                || (name.starts_with(logger.as_str()) && name[logger.len()..].starts_with("::")))
+            .max_by_key(|(logger, _)| logger.len())
+            .map(|(_, level)| level)
+            .unwrap_or_else(|| self.root_level())
+    }
+
+    fn root_level(&self) -> LevelFilter {
+        self.overrides.get(ROOT).map(|o| o.level).unwrap_or_else(|| self.raw.root().level())
+    }
+
+    /// Configured loggers and overrides, without root.
+    fn levels(&self) -> BTreeMap<String, LevelFilter> {
+        let mut levels: BTreeMap<String, LevelFilter> = self.raw.loggers().iter()
+            .map(|l| (l.name().to_string(), l.level()))
+            .collect();
+        levels.extend(self.overrides.iter()
+            .filter(|(name, _)| name.as_str() != ROOT)
+            .map(|(name, o)| (name.clone(), o.level)));
+        levels
+    }
+
+    fn configured(&self, name: &str) -> Option<LevelFilter> {
+        if name == ROOT {
+            return Some(self.raw.root().level());
+        }
+        self.raw.loggers().iter().find(|l| l.name() == name).map(|l| l.level())
+    }
+
+    fn status(&self, name: &str) -> LoggerStatus {
+        let o = self.overrides.get(name);
+        LoggerStatus {
+            name: name.to_string(),
+            level: self.effective(name),
+            configured: self.configured(name),
+            expires_at: o.and_then(|o| o.expires_at).map(|t| t.to_rfc3339()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct LoggerStatus {
+    pub name: String,
+    pub level: LevelFilter,
+    /// Level from the log4rs file, if the logger is configured there.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub configured: Option<LevelFilter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<String>,
+}
+
+#[derive(Serialize)]
+struct LoggersReport {
+    loggers: Vec<LoggerStatus>,
+}
+
+/// Body of `PUT /admin/loggers/{name}`. A `null` level removes the override. Overrides expire
+/// after `expires_in_seconds`, at most [`MAX_EXPIRES_IN_SECONDS`], or stay until removed.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LevelChange {
+    pub level: Option<LevelFilter>,
+    pub expires_in_seconds: Option<u64>,
+}
+
+/// Logger levels from the log4rs file with runtime overrides on top. Overrides survive
+/// reloads of the file and can expire, reverting to the file's level.
+#[derive(Clone)]
+pub struct LogLevels {
+    state: Arc<Mutex<State>>,
+}
+
+impl LogLevels {
+    fn new(raw: RawConfig, deserializers: Deserializers) -> Self {
+        LogLevels {
+            state: Arc::new(Mutex::new(State {
+                raw,
+                deserializers,
+                handle: None,
+                overrides: BTreeMap::new(),
+                next_id: 0,
+            })),
+        }
+    }
+
+    /// The levels of the installed logger, see [`init`].
+    pub fn global() -> LogLevels {
+        LEVELS.clone()
+    }
+
+    pub fn loggers(&self) -> Vec<LoggerStatus> {
+        let state = self.state.lock();
+        std::iter::once(ROOT.to_string())
+            .chain(state.levels().into_keys())
+            .map(|name| state.status(name.as_str()))
+            .collect()
+    }
+
+    pub fn logger(&self, name: &str) -> LoggerStatus {
+        self.state.lock().status(name)
+    }
+
+    /// Overrides the level of `name` until `expires_in` has passed, or until removed.
+    pub fn set(&self, name: &str, level: LevelFilter, expires_in: Option<Duration>) -> LoggerStatus {
+        let (status, id) = {
+            let mut state = self.state.lock();
+            state.next_id += 1;
+            let id = state.next_id;
+            let expires_at = expires_in
+                .and_then(|d| chrono::Duration::from_std(d).ok())
+                .map(|d| Utc::now() + d);
+            state.overrides.insert(name.to_string(), Override { level, expires_at, id });
+            state.apply();
+            (state.status(name), id)
+        };
+
+        if let Some(expires_in) = expires_in {
+            let levels = self.clone();
+            let name = name.to_string();
+            tokio::spawn(async move {
+                tokio::time::sleep(expires_in).await;
+                levels.expire(name.as_str(), id);
+            });
+        }
+        status
+    }
+
+    /// Removes the override of `name`, going back to the file's level.
+    pub fn reset(&self, name: &str) -> LoggerStatus {
+        let mut state = self.state.lock();
+        if state.overrides.remove(name).is_some() {
+            state.apply();
+        }
+        state.status(name)
+    }
+
+    /// Whether overriding `name` would take more than [`MAX_OVERRIDES`] overrides.
+    fn too_many_overrides(&self, name: &str) -> bool {
+        let state = self.state.lock();
+        !state.overrides.contains_key(name) && state.overrides.len() >= MAX_OVERRIDES
+    }
+
+    /// Removes override `id` unless it has been replaced since.
+    fn expire(&self, name: &str, id: u64) {
+        let mut state = self.state.lock();
+        if state.overrides.get(name).map(|o| o.id) == Some(id) {
+            state.overrides.remove(name);
+            state.apply();
+            let level = state.effective(name);
+            drop(state);
+            LOG.info(
+                &FlowContext::new("log-levels"),
+                format!("log level override for {} expired, back to {}", name, level).as_str(),
+            );
+        }
+    }
+
+    fn reload(&self, raw: RawConfig) {
+        let mut state = self.state.lock();
+        state.raw = raw;
+        state.apply();
+    }
+
+    /// `GET /admin/loggers`, `GET /admin/loggers/{name}` and, if `allow_changes`,
+    /// `PUT /admin/loggers/{name}`.
+    ///
+    /// These routes are unauthenticated: anyone who can reach them can, for example, turn on
+    /// debug logging everywhere. Serve them only on the internal monitoring port, never next to
+    /// the API. Expiry and override caps bound what a caller can do, not who can do it.
+    pub fn routes(self, allow_changes: bool) -> impl Filter<Extract=(impl warp::Reply, ), Error=warp::Rejection> + Clone {
+        let levels = warp::any().map(move || self.clone());
+
+        let list = warp::get()
+            .and(warp::path!("admin" / "loggers"))
+            .and(levels.clone())
+            .map(|levels: LogLevels| warp::reply::json(&LoggersReport { loggers: levels.loggers() }));
+
+        let get = warp::get()
+            .and(warp::path!("admin" / "loggers" / String))
+            .and(levels.clone())
+            .map(|name: String, levels: LogLevels| warp::reply::json(&levels.logger(name.as_str())));
+
+        let put = warp::put()
+            .and(warp::path!("admin" / "loggers" / String))
+            .and(warp::any().and_then(move || async move {
+                if allow_changes { Ok(()) } else { Err(warp::reject::not_found()) }
+            }).untuple_one())
+            .and(warp::body::content_length_limit(1024))
+            .and(warp::body::json())
+            .and(levels)
+            .map(change_level);
+
+        list.or(get).or(put)
+    }
+}
+
+fn change_level(name: String, change: LevelChange, levels: LogLevels) -> Response {
+    let fc = FlowContext::new("log-levels");
+    let mut problems = Vec::new();
+    if change.expires_in_seconds.unwrap_or(0) > MAX_EXPIRES_IN_SECONDS {
+        problems.push(format!("expires_in_seconds must be at most {}", MAX_EXPIRES_IN_SECONDS));
+    }
+    if change.level.is_some() && levels.too_many_overrides(name.as_str()) {
+        problems.push(format!("at most {} loggers can be overridden", MAX_OVERRIDES));
+    }
+    if !problems.is_empty() {
+        return problem_response(&fc, &AppError::Validation(problems).into());
+    }
+
+    let status = match change.level {
+        Some(level) => {
+            let status = levels.set(name.as_str(), level, change.expires_in_seconds.map(Duration::from_secs));
+            let until = status.expires_at.as_ref().map(|t| format!(" until {}", t)).unwrap_or_default();
+            LOG.info(&fc, format!("log level of {} set to {}{}", name, level, until).as_str());
+            status
+        }
+        None => {
+            let status = levels.reset(name.as_str());
+            LOG.info(&fc, format!("log level override for {} removed", name).as_str());
+            status
+        }
+    };
+    warp::reply::with_status(warp::reply::json(&status), StatusCode::OK).into_response()
+}
+
+/// Initialises log4rs from a YAML file, keeping the file's `refresh_rate` reloading while
+/// letting [`LogLevels`] change levels at runtime.
+pub fn init(path: &Path, deserializers: Deserializers) -> anyhow::Result<()> {
+    let source = fs::read_to_string(path)?;
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+    let raw: RawConfig = serde_yaml::from_str(source.as_str())?;
+    let refresh_rate = raw.refresh_rate();
+
+    {
+        let mut state = LEVELS.state.lock();
+        state.raw = raw;
+        state.deserializers = deserializers;
+        state.handle = Some(log4rs::init_config(state.config())?);
+    }
+
+    if let Some(rate) = refresh_rate {
+        let path = path.to_path_buf();
+        thread::Builder::new()
+            .name("log4rs refresh".to_string())
+            .spawn(move || watch(path, rate, modified))?;
+    }
+    Ok(())
+}
+
+/// Reloads the file whenever its modification time changes, like log4rs' own `init_file`.
+/// A file that cannot be read is retried only once its modification time changes again.
+fn watch(path: PathBuf, rate: Duration, mut modified: Option<SystemTime>) {
+    loop {
+        thread::sleep(rate);
+        let current = fs::metadata(&path).and_then(|m| m.modified()).ok();
+        if current == modified {
+            continue;
+        }
+        modified = current;
+        let reloaded = fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|source| serde_yaml::from_str::<RawConfig>(source.as_str()).map_err(anyhow::Error::from));
+        match reloaded {
+            Ok(raw) => LEVELS.reload(raw),
+            Err(e) => LOG.error(
+                &FlowContext::new("log-levels"),
+                format!("failed to reload {}: {}", path.display(), e).as_str(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use log::LevelFilter;
+    use log4rs::config::{Deserializers, RawConfig};
+    use warp::http::StatusCode;
+
+    use crate::log_levels::{LogLevels, MAX_EXPIRES_IN_SECONDS, MAX_OVERRIDES};
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    fn levels() -> LogLevels {
+        let raw: RawConfig = serde_yaml::from_str(
+            "appenders:\n  stdout:\n    kind: console\n\
+             root:\n  level: warn\n  appenders:\n  - stdout\n\
+             loggers:\n  app::backend:\n    level: info\n"
+        ).unwrap();
+        LogLevels::new(raw, Deserializers::default())
+    }
+
+    fn logger_level(levels: &LogLevels, name: &str) -> Option<LevelFilter> {
+        levels.state.lock().config().loggers().iter()
+            .find(|l| l.name() == name)
+            .map(|l| l.level())
+    }
+
+    #[test]
+    fn shows_and_changes_levels() {
+        let routes = levels().routes(true);
+
+        let res = aw!(warp::test::request().path("/admin/loggers").reply(&routes));
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            String::from_utf8_lossy(res.body()),
+            "{\"loggers\":[{\"name\":\"root\",\"level\":\"WARN\",\"configured\":\"WARN\"},\
+             {\"name\":\"app::backend\",\"level\":\"INFO\",\"configured\":\"INFO\"}]}"
+        );
+
+        let res = aw!(warp::test::request().path("/admin/loggers/app::backend::db").reply(&routes));
+        assert_eq!(String::from_utf8_lossy(res.body()), "{\"name\":\"app::backend::db\",\"level\":\"INFO\"}");
+
+        let res = aw!(warp::test::request()
+            .method("PUT")
+            .path("/admin/loggers/app::backend")
+            .json(&serde_json::json!({"level": "debug", "expires_in_seconds": 60}))
+            .reply(&routes));
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["level"], "DEBUG");
+        assert_eq!(body["configured"], "INFO");
+        assert!(body["expires_at"].is_string());
+
+        let res = aw!(warp::test::request()
+            .method("PUT")
+            .path("/admin/loggers/app::backend")
+            .json(&serde_json::json!({"level": "loud"}))
+            .reply(&routes));
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn changes_are_opt_in() {
+        let levels = levels();
+        let routes = levels.clone().routes(false);
+
+        let res = aw!(warp::test::request().path("/admin/loggers/app::backend").reply(&routes));
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = aw!(warp::test::request()
+            .method("PUT")
+            .path("/admin/loggers/app::backend")
+            .json(&serde_json::json!({"level": "debug"}))
+            .reply(&routes));
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(levels.logger("app::backend").level, LevelFilter::Info);
+    }
+
+    #[test]
+    fn caps_expiry_and_override_count() {
+        let levels = levels();
+        let routes = levels.clone().routes(true);
+        let rt = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        let put = |name: &str, body: serde_json::Value| rt.block_on(warp::test::request()
+            .method("PUT")
+            .path(format!("/admin/loggers/{}", name).as_str())
+            .json(&body)
+            .reply(&routes));
+
+        let res = put("app::backend", serde_json::json!({"level": "debug", "expires_in_seconds": MAX_EXPIRES_IN_SECONDS + 1}));
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(String::from_utf8_lossy(res.body()).contains("expires_in_seconds must be at most 86400"));
+
+        let res = put("app::backend", serde_json::json!({"level": "debug"}));
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(levels.logger("app::backend").expires_at, None);
+
+        for i in 1..MAX_OVERRIDES {
+            assert_eq!(put(format!("app::{}", i).as_str(), serde_json::json!({"level": "debug"})).status(), StatusCode::OK);
+        }
+        let res = put("app::one_too_many", serde_json::json!({"level": "debug"}));
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(put("app::backend", serde_json::json!({"level": "trace"})).status(), StatusCode::OK);
+        assert_eq!(put("app::backend", serde_json::json!({"level": null})).status(), StatusCode::OK);
+        assert_eq!(put("app::one_too_many", serde_json::json!({"level": "debug"})).status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn overrides_apply_to_config_and_expire() {
+        let levels = levels();
+        let rt = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+
+        rt.block_on(async {
+            levels.set("app::backend", LevelFilter::Debug, Some(Duration::from_millis(50)));
+            levels.set("proper_rust::access", LevelFilter::Off, None);
+            levels.set("root", LevelFilter::Error, None);
+            assert_eq!(logger_level(&levels, "app::backend"), Some(LevelFilter::Debug));
+            assert_eq!(logger_level(&levels, "proper_rust::access"), Some(LevelFilter::Off));
+            assert_eq!(levels.state.lock().config().root().level(), LevelFilter::Error);
+            assert!(levels.logger("app::backend").expires_at.is_some());
+
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        });
+
+        assert_eq!(logger_level(&levels, "app::backend"), Some(LevelFilter::Info));
+        assert_eq!(levels.logger("app::backend").expires_at, None);
+
+        levels.reset("proper_rust::access");
+        levels.reset("root");
+        assert_eq!(logger_level(&levels, "proper_rust::access"), None);
+        assert_eq!(levels.logger("anything").level, LevelFilter::Warn);
+    }
+}
@@ -0,0 +1,206 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use deadpool_postgres::Pool;
+use include_dir::{Dir, include_dir};
+use lazy_static::lazy_static;
+
+use crate::database::traced;
+use crate::flow_logger;
+use crate::flow_logger::{FlowContext, FlowLogger};
+
+static EMBEDDED_MIGRATIONS: Dir = include_dir!("$CARGO_MANIFEST_DIR/migrations");
+
+/// Advisory lock key shared by every replica running migrations.
+const MIGRATION_LOCK_KEY: i64 = 7_283_401_652;
+
+const LOCK: &str = "SELECT pg_advisory_lock($1)";
+const UNLOCK: &str = "SELECT pg_advisory_unlock($1)";
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS schema_migrations (\
+                            version BIGINT PRIMARY KEY, \
+                            name TEXT NOT NULL, \
+                            applied_at TIMESTAMPTZ NOT NULL DEFAULT now())";
+const SELECT_APPLIED: &str = "SELECT version FROM schema_migrations";
+const RECORD_APPLIED: &str = "INSERT INTO schema_migrations (version, name) VALUES ($1, $2)";
+
+lazy_static! {
+    static ref LOG: FlowLogger = flow_logger!("proper_rust::migrations");
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Migration {
+    pub version: i64,
+    pub name: String,
+    pub sql: String,
+}
+
+impl Migration {
+    /// Parses `{version}_{name}.sql`, e.g. `0001_create_chuck.sql`.
+    fn from_file(file_name: &str, sql: &str) -> Result<Migration, MigrationError> {
+        let invalid = || MigrationError::InvalidFile(file_name.to_string());
+        let stem = file_name.strip_suffix(".sql").ok_or_else(invalid)?;
+        let (version, name) = stem.split_once('_').ok_or_else(invalid)?;
+        Ok(Migration {
+            version: version.parse().map_err(|_| invalid())?,
+            name: name.to_string(),
+            sql: sql.to_string(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    InvalidFile(String),
+    DuplicateVersion(i64),
+    Io(std::io::Error),
+    Pool(String),
+    Database(tokio_postgres::Error),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::InvalidFile(name) => write!(f, "invalid migration file name {}, expected <version>_<name>.sql", name),
+            MigrationError::DuplicateVersion(version) => write!(f, "duplicate migration version {}", version),
+            MigrationError::Io(e) => write!(f, "failed to read migrations: {}", e),
+            MigrationError::Pool(e) => write!(f, "failed to get connection: {}", e),
+            MigrationError::Database(e) => write!(f, "migration failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+impl From<tokio_postgres::Error> for MigrationError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        MigrationError::Database(e)
+    }
+}
+
+fn sorted(mut migrations: Vec<Migration>) -> Result<Vec<Migration>, MigrationError> {
+    migrations.sort_by_key(|m| m.version);
+    for pair in migrations.windows(2) {
+        if pair[0].version == pair[1].version {
+            return Err(MigrationError::DuplicateVersion(pair[0].version));
+        }
+    }
+    Ok(migrations)
+}
+
+/// Migrations compiled into the binary from the crate's `migrations/` directory.
+pub fn embedded() -> Result<Vec<Migration>, MigrationError> {
+    let mut migrations = Vec::new();
+    for file in EMBEDDED_MIGRATIONS.files() {
+        let file_name = file.path().to_string_lossy();
+        if !file_name.ends_with(".sql") {
+            continue;
+        }
+        let sql = file.contents_utf8()
+            .ok_or_else(|| MigrationError::InvalidFile(file_name.to_string()))?;
+        migrations.push(Migration::from_file(file_name.as_ref(), sql)?);
+    }
+    sorted(migrations)
+}
+
+/// Migrations read at runtime from the `.sql` files in `dir`.
+pub fn from_dir(dir: &Path) -> Result<Vec<Migration>, MigrationError> {
+    let mut migrations = Vec::new();
+    for entry in fs::read_dir(dir).map_err(MigrationError::Io)? {
+        let path = entry.map_err(MigrationError::Io)?.path();
+        let file_name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        if !file_name.ends_with(".sql") {
+            continue;
+        }
+        let sql = fs::read_to_string(&path).map_err(MigrationError::Io)?;
+        migrations.push(Migration::from_file(file_name.as_str(), sql.as_str())?);
+    }
+    sorted(migrations)
+}
+
+/// Applies every migration not yet recorded in `schema_migrations`, each in its own
+/// transaction, while holding an advisory lock so concurrent replicas wait their turn.
+/// Returns the number of migrations applied.
+pub async fn run(pool: &Pool, migrations: &[Migration]) -> Result<usize, MigrationError> {
+    let fc = FlowContext::new("migrations");
+    let mut client = pool.get().await.map_err(|e| MigrationError::Pool(e.to_string()))?;
+
+    traced(&fc, LOCK, || client.execute(LOCK, &[&MIGRATION_LOCK_KEY])).await?;
+    let res = apply_pending(&fc, &mut client, migrations).await;
+    traced(&fc, UNLOCK, || client.execute(UNLOCK, &[&MIGRATION_LOCK_KEY])).await?;
+    res
+}
+
+async fn apply_pending(fc: &FlowContext, client: &mut deadpool_postgres::Client, migrations: &[Migration]) -> Result<usize, MigrationError> {
+    traced(fc, CREATE_TABLE, || client.batch_execute(CREATE_TABLE)).await?;
+
+    let applied: Vec<i64> = traced(fc, SELECT_APPLIED, || client.query(SELECT_APPLIED, &[])).await?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+
+    let mut count = 0;
+    for migration in migrations.iter().filter(|m| !applied.contains(&m.version)) {
+        LOG.info(fc, format!("applying migration {}_{}", migration.version, migration.name).as_str());
+        let tx = client.transaction().await?;
+        traced(fc, migration.sql.as_str(), || tx.batch_execute(migration.sql.as_str())).await?;
+        traced(fc, RECORD_APPLIED, || async {
+            tx.execute(RECORD_APPLIED, &[&migration.version, &migration.name]).await
+        }).await?;
+        tx.commit().await?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::database::create_pool;
+    use crate::migrations::{embedded, Migration, MigrationError, run, sorted};
+    use crate::settings::Database;
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    #[test]
+    fn parses_versioned_file_names() {
+        let migration = Migration::from_file("0002_add_index.sql", "SELECT 1").unwrap();
+        assert_eq!(migration, Migration { version: 2, name: "add_index".to_string(), sql: "SELECT 1".to_string() });
+
+        assert!(Migration::from_file("add_index.sql", "").is_err());
+        assert!(Migration::from_file("0002_add_index.txt", "").is_err());
+    }
+
+    #[test]
+    fn rejects_duplicate_versions() {
+        let a = Migration::from_file("1_a.sql", "").unwrap();
+        let b = Migration::from_file("01_b.sql", "").unwrap();
+        match sorted(vec![a, b]) {
+            Err(MigrationError::DuplicateVersion(1)) => {}
+            _ => panic!("expected duplicate version"),
+        }
+    }
+
+    #[test]
+    fn embeds_project_migrations() {
+        let migrations = embedded().unwrap();
+        assert_eq!(migrations[0].version, 1);
+        assert_eq!(migrations[0].name, "create_rust_test_chuck");
+    }
+
+    #[test]
+    fn run_applies_each_migration_once() {
+        let pool = create_pool(&Database {
+            url: "postgresql://localhost/create_drop".into(),
+            password: "asdf123".into(),
+            ..Database::default()
+        }).unwrap();
+        let migrations = embedded().unwrap();
+
+        aw!(run(&pool, &migrations)).unwrap();
+        assert_eq!(aw!(run(&pool, &migrations)).unwrap(), 0);
+    }
+}
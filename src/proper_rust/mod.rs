@@ -1,8 +1,22 @@
 pub use proper_rust::start_server;
 pub use proper_rust::setup;
+pub use proper_rust::StartupError;
+pub use health::Health;
+pub use shutdown::Shutdown;
 
+#[allow(clippy::module_inception)]
 mod proper_rust;
 pub mod monitoring;
 pub mod flow_logger;
 pub mod database;
 pub mod settings;
+pub mod http_metrics;
+pub mod shutdown;
+pub mod health;
+pub mod migrations;
+pub mod errors;
+pub mod telemetry;
+pub mod redaction;
+pub mod log_levels;
+pub mod http_client;
+pub mod circuit_breaker;
@@ -5,15 +5,46 @@ use std::time::SystemTime;
 
 use lazy_static::lazy_static;
 use parking_lot::RwLock;
-use prometheus::{Counter, Encoder, Opts, Registry, TextEncoder};
-use prometheus::core::{AtomicF64, GenericCounter};
+use prometheus::{CounterVec, Encoder, GaugeVec, HistogramOpts, HistogramVec, Opts, Registry, TextEncoder};
+use prometheus::core::Collector;
 
-type Counters = HashMap<String, GenericCounter<AtomicF64>>;
+use crate::flow_logger;
+use crate::flow_logger::{FlowContext, FlowLogger};
+use crate::settings::Monitoring;
+use crate::telemetry::{Span, SpanKind};
+
+type Key = (String, Vec<String>);
+type Counters = HashMap<Key, Metric<CounterVec>>;
+type Histograms = HashMap<Key, Metric<HistogramVec>>;
+type Gauges = HashMap<Key, Metric<GaugeVec>>;
+type Buckets = HashMap<String, Vec<f64>>;
+type ScrapeHook = Box<dyn Fn() + Send + Sync>;
+
+const OUTCOME_LABELS: [&str; 2] = ["outcome", "error_type"];
+
+lazy_static! {
+    static ref LOG: FlowLogger = flow_logger!("proper_rust::monitoring");
+}
+
+/// A metric vec and whether the registry accepted it; rejected ones still count, but are not exported.
+#[derive(Clone)]
+struct Metric<T> {
+    vec: T,
+    registered: bool,
+}
+
+fn key(name: &str, label_names: &[&str]) -> Key {
+    (name.to_string(), label_names.iter().map(|l| l.to_string()).collect())
+}
 
 #[derive(Clone)]
 struct MetricStore {
     registry: Arc<RwLock<Registry>>,
     counters: Arc<RwLock<Counters>>,
+    histograms: Arc<RwLock<Histograms>>,
+    gauges: Arc<RwLock<Gauges>>,
+    buckets: Arc<RwLock<Buckets>>,
+    scrape_hooks: Arc<RwLock<Vec<ScrapeHook>>>,
 }
 
 impl MetricStore {
@@ -21,66 +52,227 @@ impl MetricStore {
         MetricStore {
             registry: Arc::new(RwLock::new(Registry::new())),
             counters: Arc::new(RwLock::new(HashMap::new())),
+            histograms: Arc::new(RwLock::new(HashMap::new())),
+            gauges: Arc::new(RwLock::new(HashMap::new())),
+            buckets: Arc::new(RwLock::new(HashMap::new())),
+            scrape_hooks: Arc::new(RwLock::new(Vec::new())),
+        }
+    }
+
+    /// Registers `vec`, logging instead of failing when `name` is already taken with other labels.
+    fn register<T: Collector + Clone + 'static>(&self, name: &str, vec: T) -> Metric<T> {
+        let registered = match self.registry.read().register(Box::new(vec.clone())) {
+            Ok(()) => true,
+            Err(e) => {
+                LOG.error(&FlowContext::new("monitoring"), format!("metric {} is not exported: {}", name, e).as_str());
+                false
+            }
+        };
+        Metric { vec, registered }
+    }
+
+    fn counter_vec(&self, name: &str, label_names: &[&str]) -> CounterVec {
+        let key = key(name, label_names);
+        if let Some(counter) = self.counters.read().get(&key) {
+            return counter.vec.clone();
         }
+
+        let mut counters = self.counters.write();
+        counters.entry(key)
+            .or_insert_with(|| {
+                let opts = Opts::new(name, name.to_string() + " help");
+                self.register(name, CounterVec::new(opts, label_names).unwrap())
+            })
+            .vec
+            .clone()
+    }
+
+    fn histogram_vec(&self, name: &str, label_names: &[&str]) -> HistogramVec {
+        let key = key(name, label_names);
+        if let Some(histogram) = self.histograms.read().get(&key) {
+            return histogram.vec.clone();
+        }
+
+        let buckets = self.buckets.read().get(name).cloned()
+            .unwrap_or_else(|| prometheus::DEFAULT_BUCKETS.to_vec());
+
+        let mut histograms = self.histograms.write();
+        histograms.entry(key)
+            .or_insert_with(|| {
+                let opts = HistogramOpts::new(name, name.to_string() + " help").buckets(buckets);
+                self.register(name, HistogramVec::new(opts, label_names).unwrap())
+            })
+            .vec
+            .clone()
+    }
+
+    fn gauge_vec(&self, name: &str, label_names: &[&str]) -> GaugeVec {
+        let key = key(name, label_names);
+        if let Some(gauge) = self.gauges.read().get(&key) {
+            return gauge.vec.clone();
+        }
+
+        let mut gauges = self.gauges.write();
+        gauges.entry(key)
+            .or_insert_with(|| {
+                let opts = Opts::new(name, name.to_string() + " help");
+                self.register(name, GaugeVec::new(opts, label_names).unwrap())
+            })
+            .vec
+            .clone()
+    }
+
+    fn set_prefix(&self, prefix: &str) -> Result<(), prometheus::Error> {
+        let prefix = if prefix.is_empty() { None } else { Some(prefix.to_string()) };
+        let registry = Registry::new_custom(prefix, None)?;
+        for counter in self.counters.read().values().filter(|c| c.registered) {
+            registry.register(Box::new(counter.vec.clone()))?;
+        }
+        for histogram in self.histograms.read().values().filter(|h| h.registered) {
+            registry.register(Box::new(histogram.vec.clone()))?;
+        }
+        for gauge in self.gauges.read().values().filter(|g| g.registered) {
+            registry.register(Box::new(gauge.vec.clone()))?;
+        }
+        *self.registry.write() = registry;
+        Ok(())
     }
 }
 
 
 lazy_static! {
-    static ref COUNTERS: MetricStore = MetricStore::new();
-}
-
-fn inc_metric(metric_name: &str, value: f64, success: bool, error_type: &str) {
-    let key = format!("{}_{}_{}", metric_name, success, error_type);
-    if !COUNTERS.counters.read().contains_key(key.as_str()) {
-        let counter_opts = Opts::new(metric_name, metric_name.to_string() + " help");
-        let mut labels = HashMap::new();
-        let outcome = if success { "success" } else { "error" };
-        labels.insert("outcome".to_string(), outcome.to_string());
-        labels.insert("error_type".to_string(), error_type.to_string());
-        let with_labels = counter_opts.const_labels(labels);
-        let counter = Counter::with_opts(with_labels).unwrap();
-        COUNTERS.registry.read().register(Box::new(counter.clone())).unwrap();
-        COUNTERS.counters.write().insert(key.to_string(), counter);
-    }
+    static ref METRICS: MetricStore = MetricStore::new();
+}
+
+/// Prefixes every metric name with `config.prefix`, re-registering the metrics created so far.
+pub fn init_monitoring(config: &Monitoring) -> Result<(), prometheus::Error> {
+    METRICS.set_prefix(config.prefix.as_str())
+}
+
+/// Returns the counter vec named `name` with `label_names`, registering it on first use. A name
+/// already registered with other labels gets a vec that is logged once and never exported.
+pub fn counter_vec(name: &str, label_names: &[&str]) -> CounterVec {
+    METRICS.counter_vec(name, label_names)
+}
+
+/// Returns the histogram vec named `name` with `label_names`, registering it on first use.
+pub fn histogram_vec(name: &str, label_names: &[&str]) -> HistogramVec {
+    METRICS.histogram_vec(name, label_names)
+}
+
+/// Returns the gauge vec named `name` with `label_names`, registering it on first use.
+pub fn gauge_vec(name: &str, label_names: &[&str]) -> GaugeVec {
+    METRICS.gauge_vec(name, label_names)
+}
+
+/// Registers a hook run before every scrape, for gauges sampled from some other state.
+pub fn on_scrape(hook: impl Fn() + Send + Sync + 'static) {
+    METRICS.scrape_hooks.write().push(Box::new(hook));
+}
 
-    COUNTERS.counters.read().get(key.as_str()).unwrap().inc_by(value);
+/// Sets the buckets used for the histogram `name`. Only takes effect before the histogram is first used.
+pub fn set_buckets(name: &str, buckets: Vec<f64>) {
+    METRICS.buckets.write().insert(name.to_string(), buckets);
 }
 
 pub trait ErrorTagger {
     fn error_tag(&self) -> String;
 }
 
-pub async fn timed<F, T, E>(name: &str, f: impl FnOnce() -> F) -> Result<T, E>
+fn record_outcome(name: &str, seconds: f64, outcome: &str, error_type: &str) {
+    let labels = [outcome, error_type];
+    counter_vec(format!("{}_total", name).as_str(), &OUTCOME_LABELS)
+        .with_label_values(&labels)
+        .inc();
+    histogram_vec(format!("{}_time_seconds", name).as_str(), &OUTCOME_LABELS)
+        .with_label_values(&labels)
+        .observe(seconds);
+}
+
+/// Times `f` and records its outcome, running it inside a child span of `fc` named `name`.
+pub async fn timed<F, T, E>(name: &str, fc: &FlowContext, f: impl FnOnce(FlowContext) -> F) -> Result<T, E>
     where
         F: Future<Output=Result<T, E>>,
         E: ErrorTagger,
 {
+    let span = Span::start(name, SpanKind::Internal, &fc.trace);
     let start = SystemTime::now();
-    let res = f().await;
-    let duration = start.elapsed().unwrap();
+    let child = fc.with_trace(span.context().clone());
+    let res = child.clone().scope(f(child)).await;
+    let duration = start.elapsed().unwrap_or_default();
 
     match res {
         Ok(t) => {
-            inc_metric(format!("{}_total", name).as_str(), 1.0, true, "no-error");
-            inc_metric(format!("{}_time_seconds_count", name).as_str(), 1.0, true, "no-error");
-            inc_metric(format!("{}_time_seconds", name).as_str(), duration.as_secs_f64(), true, "no-error");
+            record_outcome(name, duration.as_secs_f64(), "success", "no-error");
+            span.end(None);
             Ok(t)
         }
         Err(e) => {
             let tag = e.error_tag();
-            inc_metric(format!("{}_total", name).as_str(), 1.0, false, tag.as_str());
-            inc_metric(format!("{}_time_seconds_count", name).as_str(), 1.0, false, tag.as_str());
-            inc_metric(format!("{}_time_seconds", name).as_str(), duration.as_secs_f64(), false, tag.as_str());
+            record_outcome(name, duration.as_secs_f64(), "error", tag.as_str());
+            span.end(Some(tag));
             Err(e)
         }
     }
 }
 
 pub fn metrics() -> String {
+    for hook in METRICS.scrape_hooks.read().iter() {
+        hook();
+    }
+
     let mut buffer = vec![];
     let encoder = TextEncoder::new();
-    let metric_families = COUNTERS.registry.read().gather();
+    let metric_families = METRICS.registry.read().gather();
     encoder.encode(&metric_families, &mut buffer).unwrap();
     String::from_utf8(buffer).unwrap()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use crate::flow_logger::FlowContext;
+    use crate::monitoring::{counter_vec, ErrorTagger, metrics, set_buckets, timed};
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    struct TestError;
+
+    impl ErrorTagger for TestError {
+        fn error_tag(&self) -> String {
+            "test-error".to_string()
+        }
+    }
+
+    #[test]
+    fn timed_records_histogram_with_outcome_labels() {
+        set_buckets("monitoring_test_op_time_seconds", vec![0.5, 1.0]);
+
+        let fc = FlowContext::new("monitoring-test");
+        let ok: Result<i32, TestError> = aw!(timed("monitoring_test_op", &fc, |_| async { Ok(1) }));
+        let err: Result<i32, TestError> = aw!(timed("monitoring_test_op", &fc, |_| async { Err(TestError) }));
+        assert!(ok.is_ok());
+        assert!(err.is_err());
+
+        let output = metrics();
+        assert!(output.contains("# TYPE monitoring_test_op_time_seconds histogram"));
+        assert!(output.contains("monitoring_test_op_time_seconds_bucket{error_type=\"no-error\",outcome=\"success\",le=\"0.5\"} 1"));
+        assert!(output.contains("monitoring_test_op_time_seconds_count{error_type=\"test-error\",outcome=\"error\"} 1"));
+        assert!(output.contains("monitoring_test_op_total{error_type=\"test-error\",outcome=\"error\"} 1"));
+    }
+
+    #[test]
+    fn label_mismatch_does_not_panic() {
+        counter_vec("monitoring_test_clash_total", &["kind"]).with_label_values(&["a"]).inc();
+        let other = counter_vec("monitoring_test_clash_total", &["other"]);
+        other.with_label_values(&["b"]).inc();
+        assert_eq!(counter_vec("monitoring_test_clash_total", &["other"]).with_label_values(&["b"]).get(), 1.0);
+
+        let output = metrics();
+        assert!(output.contains("monitoring_test_clash_total{kind=\"a\"} 1"));
+        assert!(!output.contains("other=\"b\""));
+    }
+}
@@ -1,44 +1,312 @@
+use std::convert::Infallible;
+use std::fmt;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::time::Duration;
+
 use deadpool_postgres::Pool;
-use futures::join;
-use warp::{Filter, Reply};
+use config::ConfigError;
+use lazy_static::lazy_static;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+use warp::{Filter, Rejection, Reply};
+use warp::hyper::{Body, Request, Server};
+use warp::hyper::service::{make_service_fn, Service, service_fn};
+
+use crate::flow_logger;
+use crate::database::{create_pool, DatabaseError, register_pool_metrics};
+use crate::errors;
+use crate::flow_logger::{ensure_flow_id, FLOW_ID_HEADER, FlowContext, FlowLogger, init_logging};
+use crate::health::Health;
+use crate::http_metrics;
+use crate::http_metrics::RouteTemplate;
+use crate::log_levels::LogLevels;
+use crate::migrations;
+use crate::migrations::MigrationError;
+use crate::monitoring;
+use crate::monitoring::init_monitoring;
+use crate::settings::{Api, ConfigSource, Database, InvalidSettings, Monitoring, Settings};
+use crate::shutdown::Shutdown;
+use crate::telemetry;
 
-use crate::proper_rust::database::create_pool;
-use crate::proper_rust::flow_logger::init_logging;
-use crate::proper_rust::monitoring;
-use crate::proper_rust::settings::{load_config, Settings};
+lazy_static! {
+    static ref LOG: FlowLogger = flow_logger!("proper_rust::shutdown");
+}
 
-pub async fn start_server<F>(filter: F)
+pub async fn start_server<F, R>(filter: F, api: &Api, monitoring: &Monitoring, health: Health, shutdown: Shutdown) -> Result<(), StartupError>
     where
-        F: Filter + Clone + Send + Sync + 'static,
-        F::Extract: Reply,
+        F: Filter<Extract=(R, ), Error=Rejection> + Clone + Send + Sync + 'static,
+        R: Reply + 'static,
 {
-    let prometheus = warp::serve(
-        warp::get()
-            .and(warp::path("metrics"))
-            .and_then(prometheus_metrics)
-    ).run(([127, 0, 0, 1], 1234));
+    let api_addr = socket_addr(api.host.as_str(), api.http_port)?;
+    let admin_addr = socket_addr(monitoring.host.as_str(), monitoring.port)?;
+    http_metrics::init_buckets();
+
+    let metrics = warp::get()
+        .and(warp::path("metrics"))
+        .and_then(prometheus_metrics);
+
+    // The admin server outlives the API drain so that readiness keeps answering 503 (and
+    // metrics keep being scraped) while in-flight requests finish.
+    let (stop_admin, admin_stopped) = oneshot::channel::<()>();
+    let (_, admin) = warp::serve(
+        metrics.or(health.routes(shutdown.clone())).or(LogLevels::global().routes(monitoring.log_level_changes))
+    ).try_bind_with_graceful_shutdown(admin_addr, async {
+        let _ = admin_stopped.await;
+    }).map_err(|e| bind_error(admin_addr, e))?;
+    let api_server = Server::try_bind(&api_addr).map_err(|e| bind_error(api_addr, e))?;
+    let admin = tokio::spawn(admin);
 
-    let app = warp::serve(filter)
-        .run(([127, 0, 0, 1], 3030));
+    let app_service = warp::service(http_metrics::instrument(errors::handle_rejections(filter)));
+    let make_service = make_service_fn(move |_| {
+        let app_service = app_service.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut req: Request<Body>| {
+                let flow_id = ensure_flow_id(req.headers_mut());
+                let method = req.method().to_string();
+                let path = req.uri().path().to_string();
+                let span = telemetry::server_span(method.as_str(), path.as_str(), req.headers_mut());
+                let fc = FlowContext::from_headers(req.headers());
+                let mut app_service = app_service.clone();
+                async move {
+                    let mut res = fc.scope(app_service.call(req)).await?;
+                    let route = res.extensions().get::<RouteTemplate>().map(|t| t.0);
+                    telemetry::end_server_span(span, method.as_str(), route, res.status());
+                    res.headers_mut().insert(FLOW_ID_HEADER, flow_id);
+                    Ok::<_, Infallible>(res)
+                }
+            }))
+        }
+    });
+    let app = async {
+        let server = api_server
+            .serve(make_service)
+            .with_graceful_shutdown(shutdown.signalled());
+        if let Err(e) = server.await {
+            LOG.error(&FlowContext::new("server"), format!("api server failed: {}", e).as_str());
+        }
+    };
+
+    tokio::spawn(shutdown.clone().listen_for_signals());
+
+    tokio::pin!(app);
+    tokio::select! {
+        _ = &mut app => {}
+        _ = shutdown.signalled() => {
+            let drain_timeout = Duration::from_secs(api.drain_timeout_seconds);
+            if timeout(drain_timeout, &mut app).await.is_err() {
+                LOG.error(&FlowContext::new("shutdown"), "drain timeout elapsed, dropping in-flight requests");
+            }
+        }
+    }
 
-    join!(prometheus, app);
+    let _ = stop_admin.send(());
+    if let Err(e) = admin.await {
+        LOG.error(&FlowContext::new("server"), format!("admin server failed: {}", e).as_str());
+    }
+    shutdown.run_hooks().await;
+    Ok(())
 }
 
-pub fn setup() -> (Settings, Option<Pool>) {
-    let config: Settings = load_config();
+#[derive(Debug)]
+pub enum StartupError {
+    Config(ConfigError),
+    Logging(anyhow::Error),
+    Database(DatabaseError),
+    Migration(MigrationError),
+    Tracing(reqwest::Error),
+    HttpClient(reqwest::Error),
+    InvalidConfig(InvalidSettings),
+    Monitoring(prometheus::Error),
+    /// A server address that does not resolve, or cannot be bound, e.g. because the port is in use.
+    Bind { addr: String, reason: String },
+}
 
-    init_logging(&config);
+impl StartupError {
+    /// Process exit code, distinct per kind of failure.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            StartupError::Config(_) => 2,
+            StartupError::Logging(_) => 3,
+            StartupError::Database(_) => 4,
+            StartupError::Migration(_) => 5,
+            StartupError::Tracing(_) => 6,
+            StartupError::HttpClient(_) => 7,
+            StartupError::InvalidConfig(_) => 8,
+            StartupError::Monitoring(_) => 9,
+            StartupError::Bind { .. } => 10,
+        }
+    }
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartupError::Config(e) => write!(f, "failed to load config: {}", e),
+            StartupError::Logging(e) => write!(f, "failed to initialise logging: {:#}", e),
+            StartupError::Database(e) => write!(f, "failed to set up database: {}", e),
+            StartupError::Migration(e) => write!(f, "failed to migrate database: {}", e),
+            StartupError::Tracing(e) => write!(f, "failed to initialise tracing: {}", e),
+            StartupError::HttpClient(e) => write!(f, "failed to build http client: {}", e),
+            StartupError::InvalidConfig(e) => write!(f, "invalid config, refusing to start: {}", e),
+            StartupError::Monitoring(e) => write!(f, "failed to initialise monitoring: {}", e),
+            StartupError::Bind { addr, reason } => write!(f, "failed to bind {}: {}", addr, reason),
+        }
+    }
+}
+
+impl std::error::Error for StartupError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StartupError::Config(e) => Some(e),
+            StartupError::Logging(e) => Some(e.as_ref()),
+            StartupError::Database(e) => Some(e),
+            StartupError::Migration(e) => Some(e),
+            StartupError::Tracing(e) => Some(e),
+            StartupError::HttpClient(e) => Some(e),
+            StartupError::InvalidConfig(e) => Some(e),
+            StartupError::Monitoring(e) => Some(e),
+            StartupError::Bind { .. } => None,
+        }
+    }
+}
+
+pub async fn setup(source: &ConfigSource) -> Result<(Settings, Option<Pool>), StartupError> {
+    let config = Settings::new(source).map_err(StartupError::Config)?;
+    config.check().map_err(StartupError::InvalidConfig)?;
+
+    init_logging(&config).map_err(StartupError::Logging)?;
+    init_monitoring(&config.monitoring).map_err(StartupError::Monitoring)?;
+    telemetry::init_tracing(&config.tracing, &config.service).map_err(StartupError::Tracing)?;
 
     let pool_opt = if config.database.enabled {
-        let pool = create_pool(&config.database);
+        let pool = create_pool(&config.database).map_err(StartupError::Database)?;
+        register_pool_metrics(&pool);
+        if config.database.migrate_on_start {
+            migrate(&pool, &config.database).await.map_err(StartupError::Migration)?;
+        }
         Some(pool)
     } else {
         None
     };
 
-    (config, pool_opt)
+    Ok((config, pool_opt))
+}
+
+async fn migrate(pool: &Pool, database: &Database) -> Result<usize, MigrationError> {
+    let migrations = match &database.migrations_dir {
+        Some(dir) => migrations::from_dir(Path::new(dir))?,
+        None => migrations::embedded()?,
+    };
+    migrations::run(pool, &migrations).await
+}
+
+fn socket_addr(host: &str, port: u16) -> Result<SocketAddr, StartupError> {
+    (host, port).to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| StartupError::Bind {
+            addr: format!("{}:{}", host, port),
+            reason: "does not resolve to a socket address".to_string(),
+        })
+}
+
+fn bind_error(addr: SocketAddr, e: impl fmt::Display) -> StartupError {
+    StartupError::Bind { addr: addr.to_string(), reason: e.to_string() }
 }
 
 async fn prometheus_metrics() -> Result<impl warp::Reply, warp::Rejection> {
-    Ok(format!("{}", monitoring::metrics()))
+    Ok(monitoring::metrics())
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    use warp::Filter;
+
+    use crate::health::Health;
+    use crate::proper_rust::{socket_addr, start_server, StartupError};
+    use crate::settings::{Api, Monitoring};
+    use crate::shutdown::Shutdown;
+
+    fn free_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn bad_bind_addresses_fail_startup() {
+        assert_eq!(socket_addr("127.0.0.1", 8080).unwrap().port(), 8080);
+        match socket_addr("not a host!", 8080) {
+            Err(e @ StartupError::Bind { .. }) => {
+                assert_eq!(e.exit_code(), 10);
+                assert_eq!(e.to_string(), "failed to bind not a host!:8080: does not resolve to a socket address");
+            }
+            other => panic!("expected a bind error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn ports_in_use_fail_startup() {
+        let rt = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let api = Api { host: "127.0.0.1".to_string(), http_port: port, drain_timeout_seconds: 5 };
+        let monitoring = Monitoring { host: "127.0.0.1".to_string(), port: free_port(), prefix: "".to_string(), check_timeout_ms: 5000, log_level_changes: false };
+
+        let res = rt.block_on(start_server(warp::path("up").map(warp::reply), &api, &monitoring, Health::new(), Shutdown::new()));
+        match res {
+            Err(e @ StartupError::Bind { .. }) => {
+                assert_eq!(e.exit_code(), 10);
+                assert!(e.to_string().starts_with(format!("failed to bind 127.0.0.1:{}: ", port).as_str()), "{}", e);
+            }
+            other => panic!("expected a bind error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn readiness_reports_down_until_the_api_has_drained() {
+        let rt = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        let api = Api { host: "127.0.0.1".to_string(), http_port: free_port(), drain_timeout_seconds: 5 };
+        let monitoring = Monitoring { host: "127.0.0.1".to_string(), port: free_port(), prefix: "".to_string(), check_timeout_ms: 5000, log_level_changes: false };
+        let slow_url = format!("http://127.0.0.1:{}/slow", api.http_port);
+        let ready_url = format!("http://127.0.0.1:{}/health/ready", monitoring.port);
+        let shutdown = Shutdown::new();
+
+        rt.block_on(async {
+            let slow = warp::path("slow").and_then(|| async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                Ok::<_, warp::Rejection>("done")
+            });
+            let server = tokio::spawn({
+                let shutdown = shutdown.clone();
+                async move { start_server(slow, &api, &monitoring, Health::new(), shutdown).await.unwrap() }
+            });
+
+            let client = reqwest::Client::new();
+            let mut ready = None;
+            for _ in 0..50 {
+                if let Ok(res) = client.get(ready_url.as_str()).send().await {
+                    ready = Some(res.status());
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            assert_eq!(ready, Some(reqwest::StatusCode::OK));
+
+            let in_flight = tokio::spawn(client.get(slow_url.as_str()).send());
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            shutdown.trigger();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            let res = client.get(ready_url.as_str()).send().await.unwrap();
+            assert_eq!(res.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+
+            let res = in_flight.await.unwrap().unwrap();
+            assert_eq!(res.text().await.unwrap(), "done");
+            server.await.unwrap();
+            assert!(client.get(ready_url.as_str()).send().await.is_err());
+        });
+    }
 }
@@ -0,0 +1,128 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+use crate::flow_logger;
+use crate::flow_logger::{FlowContext, FlowLogger};
+
+type Hook = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output=()> + Send>> + Send>;
+
+lazy_static! {
+    static ref LOG: FlowLogger = flow_logger!("proper_rust::shutdown");
+}
+
+/// Shared shutdown signal plus the hooks to run once the servers have drained.
+#[derive(Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+    hooks: Arc<Mutex<Vec<(String, Hook)>>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        Shutdown {
+            sender: Arc::new(sender),
+            receiver,
+            hooks: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Registers an async hook, e.g. closing a `Pool`. Hooks run in registration order.
+    pub fn on_shutdown<F, Fut>(&self, name: &str, hook: F)
+        where
+            F: FnOnce() -> Fut + Send + 'static,
+            Fut: Future<Output=()> + Send + 'static,
+    {
+        let hook: Hook = Box::new(move || Box::pin(hook()));
+        self.hooks.lock().push((name.to_string(), hook));
+    }
+
+    pub fn trigger(&self) {
+        let _ = self.sender.send(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Resolves once shutdown has been triggered.
+    pub fn signalled(&self) -> impl Future<Output=()> + Send + 'static {
+        let mut receiver = self.receiver.clone();
+        async move {
+            while !*receiver.borrow() {
+                if receiver.changed().await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Triggers shutdown on the first SIGTERM or SIGINT.
+    pub async fn listen_for_signals(self) {
+        let fc = FlowContext::new("shutdown");
+        let mut sigterm = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+        let mut sigint = signal(SignalKind::interrupt()).expect("failed to install SIGINT handler");
+
+        tokio::select! {
+            _ = sigterm.recv() => LOG.info(&fc, "received SIGTERM, shutting down"),
+            _ = sigint.recv() => LOG.info(&fc, "received SIGINT, shutting down"),
+            _ = self.signalled() => return,
+        }
+        self.trigger();
+    }
+
+    pub async fn run_hooks(&self) {
+        let fc = FlowContext::new("shutdown");
+        let hooks: Vec<(String, Hook)> = self.hooks.lock().drain(..).collect();
+        for (name, hook) in hooks {
+            LOG.info(&fc, format!("running shutdown hook {}", name).as_str());
+            hook().await;
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::shutdown::Shutdown;
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    #[test]
+    fn trigger_releases_waiters_and_hooks_run_once() {
+        let shutdown = Shutdown::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let hook_calls = calls.clone();
+        shutdown.on_shutdown("count", move || async move {
+            hook_calls.fetch_add(1, Ordering::SeqCst);
+        });
+
+        let waiter = shutdown.signalled();
+        shutdown.trigger();
+        aw!(waiter);
+        assert!(shutdown.is_triggered());
+
+        aw!(shutdown.run_hooks());
+        aw!(shutdown.run_hooks());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
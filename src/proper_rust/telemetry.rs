@@ -0,0 +1,414 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use parking_lot::{Mutex, RwLock};
+use rand::Rng;
+use serde_json::{json, Value};
+use warp::http::{HeaderMap, HeaderValue, StatusCode};
+
+use crate::flow_logger;
+use crate::flow_logger::{FlowContext, FlowLogger};
+use crate::settings::{LoggingMeta, Tracing};
+
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+pub const TRACESTATE_HEADER: &str = "tracestate";
+
+lazy_static! {
+    static ref LOG: FlowLogger = flow_logger!("proper_rust::telemetry");
+    static ref EXPORTER: RwLock<Option<Exporter>> = RwLock::new(None);
+    static ref PENDING: Mutex<Vec<SpanData>> = Mutex::new(Vec::new());
+}
+
+/// W3C trace context (`traceparent`/`tracestate`) of the current span.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub span_id: String,
+    pub sampled: bool,
+    pub trace_state: Option<String>,
+}
+
+impl TraceContext {
+    /// Starts a new, sampled trace.
+    pub fn new_root() -> TraceContext {
+        TraceContext {
+            trace_id: random_id(16),
+            span_id: random_id(8),
+            sampled: true,
+            trace_state: None,
+        }
+    }
+
+    /// Parses a `traceparent` header such as
+    /// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+    pub fn parse(traceparent: &str, trace_state: Option<&str>) -> Option<TraceContext> {
+        let parts: Vec<&str> = traceparent.trim().split('-').collect();
+        if parts.len() < 4 || parts[0] == "ff" || (parts[0] == "00" && parts.len() != 4) {
+            return None;
+        }
+        let (version, trace_id, span_id, flags) = (parts[0], parts[1], parts[2], parts[3]);
+        if !is_hex(version, 2) || !is_hex(trace_id, 32) || !is_hex(span_id, 16) || !is_hex(flags, 2) {
+            return None;
+        }
+        if trace_id.chars().all(|c| c == '0') || span_id.chars().all(|c| c == '0') {
+            return None;
+        }
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+        Some(TraceContext {
+            trace_id: trace_id.to_string(),
+            span_id: span_id.to_string(),
+            sampled: flags & 1 == 1,
+            trace_state: trace_state.map(|s| s.to_string()).filter(|s| !s.is_empty()),
+        })
+    }
+
+    /// Reads the context from request headers, if a valid `traceparent` is present.
+    pub fn from_headers(headers: &HeaderMap) -> Option<TraceContext> {
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+        TraceContext::parse(header(TRACEPARENT_HEADER)?, header(TRACESTATE_HEADER))
+    }
+
+    /// A new span in the same trace.
+    pub fn child(&self) -> TraceContext {
+        TraceContext {
+            span_id: random_id(8),
+            ..self.clone()
+        }
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{}", self.trace_id, self.span_id, if self.sampled { "01" } else { "00" })
+    }
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+/// Lower-case hex of `bytes` random bytes, never all zero (an invalid W3C id).
+fn random_id(bytes: usize) -> String {
+    let mut rng = rand::thread_rng();
+    let mut id = vec![0u8; bytes];
+    while id.iter().all(|b| *b == 0) {
+        rng.fill(id.as_mut_slice());
+    }
+    id.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpanKind {
+    Internal,
+    Server,
+    Client,
+}
+
+impl SpanKind {
+    fn otlp(self) -> u8 {
+        match self {
+            SpanKind::Internal => 1,
+            SpanKind::Server => 2,
+            SpanKind::Client => 3,
+        }
+    }
+}
+
+/// An in-progress span; recorded for export when [`Span::end`] is called.
+pub struct Span {
+    name: String,
+    kind: SpanKind,
+    context: TraceContext,
+    parent_span_id: Option<String>,
+    start: SystemTime,
+    attributes: Vec<(String, String)>,
+}
+
+impl Span {
+    /// Starts a child span of `parent`.
+    pub fn start(name: &str, kind: SpanKind, parent: &TraceContext) -> Span {
+        Span {
+            name: name.to_string(),
+            kind,
+            context: parent.child(),
+            parent_span_id: Some(parent.span_id.clone()),
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+        }
+    }
+
+    fn root(name: &str, kind: SpanKind) -> Span {
+        Span {
+            name: name.to_string(),
+            kind,
+            context: TraceContext::new_root(),
+            parent_span_id: None,
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+        }
+    }
+
+    pub fn context(&self) -> &TraceContext {
+        &self.context
+    }
+
+    pub fn set_name(&mut self, name: String) {
+        self.name = name;
+    }
+
+    pub fn set_attribute(&mut self, key: &str, value: impl ToString) {
+        self.attributes.push((key.to_string(), value.to_string()));
+    }
+
+    /// Ends the span, marking it failed with `error` if given.
+    pub fn end(self, error: Option<String>) {
+        if !self.context.sampled || EXPORTER.read().is_none() {
+            return;
+        }
+        let data = SpanData {
+            name: self.name,
+            kind: self.kind,
+            context: self.context,
+            parent_span_id: self.parent_span_id,
+            start: self.start,
+            end: SystemTime::now(),
+            attributes: self.attributes,
+            error,
+        };
+        let max_queue_size = EXPORTER.read().as_ref().map(|e| e.max_queue_size).unwrap_or(0);
+        let mut pending = PENDING.lock();
+        if pending.len() < max_queue_size {
+            pending.push(data);
+        }
+    }
+}
+
+/// Starts the server span for an incoming request, continuing the caller's trace when a
+/// valid `traceparent` was sent. The request's trace headers are rewritten to the new span
+/// so every filter extracting a [`FlowContext`] sees it as the current span.
+pub fn server_span(method: &str, path: &str, headers: &mut HeaderMap) -> Span {
+    let name = format!("{} {}", method, path);
+    let mut span = match TraceContext::from_headers(headers) {
+        Some(parent) => Span::start(name.as_str(), SpanKind::Server, &parent),
+        None => Span::root(name.as_str(), SpanKind::Server),
+    };
+    span.set_attribute("http.method", method);
+    span.set_attribute("http.target", path);
+
+    if let Ok(value) = HeaderValue::from_str(span.context().traceparent().as_str()) {
+        headers.insert(TRACEPARENT_HEADER, value);
+    }
+    span
+}
+
+/// Ends a server span with the response status, naming it after the matched route.
+pub fn end_server_span(mut span: Span, method: &str, route: Option<&str>, status: StatusCode) {
+    if let Some(route) = route {
+        span.set_name(format!("{} {}", method, route));
+        span.set_attribute("http.route", route);
+    }
+    span.set_attribute("http.status_code", status.as_u16());
+    let error = if status.is_server_error() { Some(status.to_string()) } else { None };
+    span.end(error);
+}
+
+struct SpanData {
+    name: String,
+    kind: SpanKind,
+    context: TraceContext,
+    parent_span_id: Option<String>,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(String, String)>,
+    error: Option<String>,
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+fn string_attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+impl SpanData {
+    fn otlp(&self) -> Value {
+        let status = match &self.error {
+            Some(message) => json!({ "code": 2, "message": message }),
+            None => json!({ "code": 0 }),
+        };
+        json!({
+            "traceId": self.context.trace_id,
+            "spanId": self.context.span_id,
+            "parentSpanId": self.parent_span_id.clone().unwrap_or_default(),
+            "traceState": self.context.trace_state.clone().unwrap_or_default(),
+            "name": self.name,
+            "kind": self.kind.otlp(),
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(self.end),
+            "attributes": self.attributes.iter()
+                .map(|(k, v)| string_attribute(k, v))
+                .collect::<Vec<_>>(),
+            "status": status,
+        })
+    }
+}
+
+struct Exporter {
+    endpoint: String,
+    service: LoggingMeta,
+    max_queue_size: usize,
+    client: reqwest::Client,
+}
+
+impl Exporter {
+    fn new(tracing: &Tracing, service: &LoggingMeta) -> Result<Exporter, reqwest::Error> {
+        Ok(Exporter {
+            endpoint: tracing.endpoint.clone(),
+            service: service.clone(),
+            max_queue_size: tracing.max_queue_size,
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_millis(tracing.export_timeout_ms))
+                .build()?,
+        })
+    }
+
+    /// OTLP/HTTP JSON request body for `spans`.
+    fn body(&self, spans: &[SpanData]) -> Value {
+        json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [
+                        string_attribute("service.name", self.service.name.as_str()),
+                        string_attribute("service.version", self.service.version.as_str()),
+                    ]
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "proper_rust" },
+                    "spans": spans.iter().map(SpanData::otlp).collect::<Vec<_>>(),
+                }]
+            }]
+        })
+    }
+}
+
+fn install(exporter: Exporter) {
+    *EXPORTER.write() = Some(exporter);
+}
+
+/// Enables span export when `tracing.enabled`, flushing every `export_interval_ms`.
+pub fn init_tracing(tracing: &Tracing, service: &LoggingMeta) -> Result<(), reqwest::Error> {
+    if !tracing.enabled {
+        return Ok(());
+    }
+    install(Exporter::new(tracing, service)?);
+
+    let interval = Duration::from_millis(tracing.export_interval_ms);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            flush().await;
+        }
+    });
+    Ok(())
+}
+
+/// Sends all pending spans to the collector, returning how many were exported.
+pub async fn flush() -> usize {
+    let spans: Vec<SpanData> = PENDING.lock().drain(..).collect();
+    if spans.is_empty() {
+        return 0;
+    }
+
+    let request = match EXPORTER.read().as_ref() {
+        Some(exporter) => exporter.client.post(exporter.endpoint.as_str()).json(&exporter.body(&spans)),
+        None => return 0,
+    };
+    let res = request.send().await.and_then(|res| res.error_for_status());
+    match res {
+        Ok(_) => spans.len(),
+        Err(e) => {
+            LOG.error_with(&FlowContext::new("telemetry"), format!("failed to export {} spans", spans.len()).as_str(), &e);
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mockito::{Matcher, mock};
+
+    use crate::settings::{LoggingMeta, Tracing};
+    use crate::telemetry::{EXPORTER, Exporter, flush, install, PENDING, random_id, Span, SpanKind, TraceContext};
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    /// Uninstalls the exporter when dropped, so spans of other tests are never exported.
+    struct Installed;
+
+    impl Drop for Installed {
+        fn drop(&mut self) {
+            *EXPORTER.write() = None;
+            PENDING.lock().clear();
+        }
+    }
+
+    #[test]
+    fn ids_are_random_hex() {
+        let ids: Vec<String> = (0..100).map(|_| random_id(16)).collect();
+        assert!(ids.iter().all(|id| id.len() == 32 && id.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))));
+        // A UUID v4 always has version nibble 4 at position 12; random ids must not.
+        assert!(ids.iter().any(|id| &id[12..13] != "4"));
+        assert_eq!(random_id(8).len(), 16);
+    }
+
+    #[test]
+    fn parses_and_formats_traceparent() {
+        let tc = TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01", Some("vendor=1")).unwrap();
+        assert_eq!(tc.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(tc.span_id, "00f067aa0ba902b7");
+        assert!(tc.sampled);
+        assert_eq!(tc.trace_state, Some("vendor=1".to_string()));
+        assert_eq!(tc.traceparent(), "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01");
+
+        let child = tc.child();
+        assert_eq!(child.trace_id, tc.trace_id);
+        assert_ne!(child.span_id, tc.span_id);
+
+        assert!(!TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00", None).unwrap().sampled);
+        assert!(TraceContext::parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01", None).is_none());
+        assert!(TraceContext::parse("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01", None).is_none());
+        assert!(TraceContext::parse("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01", None).is_none());
+        assert!(TraceContext::parse("garbage", None).is_none());
+    }
+
+    #[test]
+    fn exports_spans_over_otlp_http() {
+        let _m = mock("POST", "/v1/traces")
+            .match_header("content-type", "application/json")
+            .match_body(Matcher::Regex("\"name\":\"telemetry_test_span\",\"parentSpanId\":\"00f067aa0ba902b7\"".to_string()))
+            .with_status(200)
+            .create();
+
+        let tracing = Tracing {
+            enabled: true,
+            endpoint: [mockito::SERVER_URL, "/v1/traces"].join(""),
+            export_interval_ms: 5000,
+            export_timeout_ms: 1000,
+            max_queue_size: 100,
+        };
+        let service = LoggingMeta { build_time: "".to_string(), name: "test".to_string(), version: "1".to_string(), environment: None };
+        install(Exporter::new(&tracing, &service).unwrap());
+        let _installed = Installed;
+
+        let parent = TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01", None).unwrap();
+        let mut span = Span::start("telemetry_test_span", SpanKind::Internal, &parent);
+        span.set_attribute("db.system", "postgresql");
+        span.end(None);
+
+        assert!(aw!(flush()) >= 1);
+        _m.assert();
+    }
+}